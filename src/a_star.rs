@@ -1,190 +1,189 @@
 use crate::environment::MovementGrid;
-use crate::movable::{
-    get_neighbours, heuristical_distance, inertia_based_inter_cell_movement, Heading, MoveCommand,
-    MovementPath, NodeCoords, PathNode, DISTANCE_FACTOR,
+use crate::formation::SpeedLimit;
+use crate::hpa_star::{ClusterGraph, HierarchicalRoute};
+use crate::movable::{current_heading, MoveCommand, MovementLimits, MovementPath};
+use crate::obstacles::collider_radius;
+use crate::pathfinding::{
+    nearest_free_cell, smooth_path, PathError, PathNode, PathSearch, SearchProgress,
 };
+use crate::stats::Stats;
 use bevy::prelude::*;
-use std::collections::{HashMap, HashSet};
-use strum::IntoEnumIterator;
+use bevy_rapier3d::geometry::Collider;
 
-#[derive(Component)]
-pub struct AStarParams {
-    movement_grid: Vec<Vec<HashMap<Heading, AStarNode>>>,
-    open_set: HashSet<NodeCoords>,
-    target: UVec2,
-    came_from: HashMap<NodeCoords, NodeCoords>,
+#[derive(Resource)]
+pub struct PathfindingSettings {
+    /// Nodes all searching units may expand together per frame
+    pub node_budget: usize,
+    /// Cells around a blocked target searched for a free one to go to instead
+    pub max_goal_distance: u32,
+}
+impl Default for PathfindingSettings {
+    fn default() -> Self {
+        PathfindingSettings {
+            node_budget: 50_000,
+            max_goal_distance: 50,
+        }
+    }
 }
-#[derive(Hash, Eq, PartialEq, Clone, Copy, Debug)]
-struct AStarNode {
-    f_score: i32,
-    g_score: i32,
-    came_from: Option<UVec2>,
+/// Sent when a unit gives up on reaching its target
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PathFailed {
+    pub entity: Entity,
+    pub target: Vec2,
+    pub error: PathError,
 }
-fn reconstruct_path(
-    came_from: &HashMap<NodeCoords, NodeCoords>,
-    end: NodeCoords,
-    gridmap: &MovementGrid,
-) -> Vec<PathNode> {
-    let mut total_path: Vec<PathNode> = vec![];
-
-    let mut current: NodeCoords = end;
-    current = came_from[&current];
-    let endnode: PathNode = PathNode {
-        xy: current.xy.as_vec2() - gridmap.settings.xy_offset,
-        h: end.h.unwrap_or_default(),
-    };
 
-    total_path.push(endnode);
-    while came_from.contains_key(&current) {
-        current = came_from[&current];
-        total_path.push(PathNode {
-            xy: (current.xy.as_vec2() - gridmap.settings.xy_offset) * gridmap.settings.cell_size,
-            h: current.h.unwrap_or_default(),
-        });
-    }
-    total_path
+/// Search of a unit which is still looking for its path
+#[derive(Component)]
+pub struct AStarParams {
+    pub search: PathSearch,
 }
 pub fn a_star(
-    movables: Query<(Entity, &mut Transform, &mut MoveCommand), Without<MovementPath>>,
+    movables: Query<
+        (
+            Entity,
+            &Transform,
+            &MoveCommand,
+            Option<&Stats>,
+            Option<&SpeedLimit>,
+        ),
+        Without<MovementPath>,
+    >,
     gridmap: Res<MovementGrid>,
+    cluster_graph: Res<ClusterGraph>,
+    pathfinding_settings: Res<PathfindingSettings>,
+    mut path_failures: EventWriter<PathFailed>,
     mut commands: Commands,
 ) {
-    for (entity, transform, movcmd) in movables.iter() {
-        if transform.translation.x == movcmd.target.x && transform.translation.y == movcmd.target.y
-        {
-            commands.entity(entity).remove::<MoveCommand>();
+    for (entity, transform, movcmd, stats, speed_limit) in movables.iter() {
+        commands
+            .entity(entity)
+            .remove::<(HierarchicalRoute, MoveCommand)>();
+        if transform.translation.xz() == movcmd.target {
             continue;
         }
-        let target: UVec2 =
-            (movcmd.target / gridmap.settings.cell_size + gridmap.settings.xy_offset).as_uvec2();
-        let start: UVec2 = UVec2 {
-            x: (transform.translation.x / gridmap.settings.cell_size + gridmap.settings.xy_offset.x)
-                as u32,
-            y: (transform.translation.z / gridmap.settings.cell_size + gridmap.settings.xy_offset.y)
-                as u32,
+        // Units and targets off the grid use the closest cell on it
+        let (Some(start), Some(requested)) = (
+            gridmap.clamped_cell_at(transform.translation.xz()),
+            gridmap.clamped_cell_at(movcmd.target),
+        ) else {
+            warn!("Move order without a movement grid");
+            continue;
         };
-        let mut movement_grid: Vec<Vec<HashMap<Heading, AStarNode>>> = vec![
-            vec![
-                Heading::iter()
-                    .map(|x| (
-                        x,
-                        AStarNode {
-                            f_score: -1,
-                            g_score: -1,
-                            came_from: None
-                        }
-                    ))
-                    .collect();
-                gridmap.grid.len()
-            ];
-            gridmap.grid[0].len()
-        ];
-        movement_grid[start.x as usize][start.y as usize]
-            .get_mut(&Heading::N)
-            .unwrap()
-            .g_score = 0;
-        commands
-            .entity(entity)
-            .insert(AStarParams {
-                movement_grid,
-                open_set: HashSet::from([NodeCoords {
-                    xy: start,
-                    h: Some(Heading::N),
-                }]),
-                came_from: HashMap::new(),
-                target,
-            })
-            .remove::<MoveCommand>();
+        let Some(target) =
+            nearest_free_cell(&gridmap, requested, pathfinding_settings.max_goal_distance)
+        else {
+            warn!("No free cell near {}", requested);
+            path_failures.send(PathFailed {
+                entity,
+                target: movcmd.target,
+                error: PathError::GoalBlocked(requested),
+            });
+            continue;
+        };
+        // Targets in other clusters are approached over a coarse route, searching only up to
+        // the next cluster at a time
+        let mut waypoints: Vec<UVec2> = match cluster_graph.route(&gridmap, start, target) {
+            Ok(waypoints) => waypoints,
+            Err(error) => {
+                warn!("{}", error);
+                path_failures.send(PathFailed {
+                    entity,
+                    target: movcmd.target,
+                    error,
+                });
+                continue;
+            }
+        };
+        waypoints.reverse();
+        let waypoint: UVec2 = waypoints.pop().unwrap_or(target);
+        if !waypoints.is_empty() {
+            commands
+                .entity(entity)
+                .insert(HierarchicalRoute { waypoints });
+        }
+        let turning_radius: f32 = MovementLimits::new(stats, speed_limit).turning_radius(&gridmap);
+        match PathSearch::new(
+            &gridmap,
+            start,
+            current_heading(transform),
+            waypoint,
+            turning_radius,
+        ) {
+            Ok(search) => {
+                commands.entity(entity).insert(AStarParams { search });
+            }
+            Err(error) => {
+                warn!("{}", error);
+                path_failures.send(PathFailed {
+                    entity,
+                    target: movcmd.target,
+                    error,
+                });
+            }
+        }
     }
 }
+/// Advances all running searches, sharing the node budget of the frame evenly between them.
+/// Budget left over by searches which finish early goes to the remaining ones. Found paths are
+/// straightened wherever the unit's collider fits through.
 pub fn calculate_a_star(
-    mut movables: Query<(Entity, &mut AStarParams), Without<MovementPath>>,
+    mut movables: Query<
+        (
+            Entity,
+            &mut AStarParams,
+            Option<&Collider>,
+            Option<&HierarchicalRoute>,
+        ),
+        Without<MovementPath>,
+    >,
     gridmap: Res<MovementGrid>,
+    pathfinding_settings: Res<PathfindingSettings>,
+    mut path_failures: EventWriter<PathFailed>,
     mut commands: Commands,
-    // mut meshes: ResMut<Assets<Mesh>>,
-    // mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    for (entity, mut params) in movables.iter_mut() {
-        let mut current: NodeCoords = NodeCoords {
-            xy: UVec2::ZERO,
-            h: Some(Heading::N),
-        };
-        let mut current_cost = 0;
-        for open_cell in &params.open_set {
-            let cell: &AStarNode = params.movement_grid[open_cell.xy.x as usize]
-                [open_cell.xy.y as usize]
-                .get(&open_cell.h.unwrap_or_default())
-                .unwrap();
-            let cell_f_score: i32 = cell.f_score;
-            if current_cost == 0 || cell_f_score < current_cost {
-                current = *open_cell;
-                current_cost = cell_f_score;
+    let mut budget: usize = pathfinding_settings.node_budget;
+    let mut remaining_searches: usize = movables.iter().count();
+    for (entity, mut params, collider, route) in movables.iter_mut() {
+        let mut share: usize = (budget / remaining_searches).max(1);
+        let granted: usize = share;
+        remaining_searches -= 1;
+        let progress: SearchProgress = params.search.expand(&gridmap, &mut share);
+        budget = budget.saturating_sub(granted - share);
+        match progress {
+            SearchProgress::Searching => {}
+            SearchProgress::Found(end) => {
+                let clearance: u32 = collider.map_or(0, |collider| {
+                    (collider_radius(collider) / gridmap.settings.cell_size) as u32
+                });
+                let mut path: Vec<PathNode> = smooth_path(
+                    &gridmap,
+                    params.search.start(),
+                    &params.search.path(end, &gridmap),
+                    clearance,
+                );
+                path.reverse();
+                commands
+                    .entity(entity)
+                    .insert(MovementPath { path })
+                    .remove::<AStarParams>();
             }
-        }
-
-        // let current_real: Vec2 =
-        //     (current.xy.as_vec2() - gridmap.settings.xy_offset) * gridmap.settings.cell_size;
-        //        commands.spawn(PbrBundle {
-        //            mesh: meshes.add(Mesh::from(shape::Plane {
-        //                size: 0.5,
-        //                subdivisions: 1,
-        //            })),
-        //            material: materials.add(Color::YELLOW.into()),
-        //            transform: Transform::from_xyz(current_real.x, 0.5, current_real.y),
-        //            ..default()
-        //        });
-        let current_node: AStarNode = params.movement_grid[current.xy.x as usize]
-            [current.xy.y as usize]
-            .get(&current.h.unwrap_or_default())
-            .unwrap()
-            .to_owned();
-
-        if current.xy == params.target {
-            let mut movementpath: MovementPath = MovementPath { path: Vec::new() };
-
-            reconstruct_path(&params.came_from, current, &gridmap)
-                .iter()
-                .enumerate()
-                .for_each(|(i, x)| {
-                    if i != 0 {
-                        movementpath.path.push(*x);
-                    }
+            SearchProgress::Exhausted => {
+                warn!("No path to {}", params.search.target());
+                // The route's first waypoint is the unit's actual goal
+                let goal: UVec2 = route
+                    .and_then(|route| route.waypoints.first().copied())
+                    .unwrap_or(params.search.target());
+                path_failures.send(PathFailed {
+                    entity,
+                    target: gridmap.cell_center(goal),
+                    error: PathError::Unreachable(params.search.target()),
                 });
-            commands.entity(entity).insert(movementpath);
-            commands.entity(entity).remove::<AStarParams>();
-            return;
-        }
-        params.open_set.remove(&current);
-        let neighbours = get_neighbours(current.xy, &gridmap);
-        let target: UVec2 = params.target;
-        for neighbour in neighbours {
-            let neighbour_node: &mut AStarNode = params.movement_grid[neighbour.xy.x as usize]
-                [neighbour.xy.y as usize]
-                .get_mut(&neighbour.h.unwrap_or_default())
-                .unwrap();
-            let tentative_g_score: i32 = current_node.g_score
-                + (inertia_based_inter_cell_movement(current, neighbour) * DISTANCE_FACTOR) as i32;
-
-            if tentative_g_score < neighbour_node.g_score || neighbour_node.g_score == -1 {
-                neighbour_node.g_score = tentative_g_score;
-                neighbour_node.f_score = tentative_g_score
-                    + (heuristical_distance(
-                        neighbour,
-                        NodeCoords {
-                            xy: target,
-                            h: None,
-                        },
-                    ) * DISTANCE_FACTOR) as i32;
-                params.came_from.insert(neighbour, current);
-                params.open_set.insert(neighbour);
+                commands
+                    .entity(entity)
+                    .remove::<AStarParams>()
+                    .remove::<HierarchicalRoute>();
             }
-            // println!(
-            //     "F Score {}",
-            //     params.movement_grid[neighbour.xy.x as usize][neighbour.xy.y as usize]
-            //         .get_mut(&neighbour.h.unwrap_or_default())
-            //         .unwrap()
-            //         .f_score
-            // );
         }
     }
 }
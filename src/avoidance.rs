@@ -0,0 +1,315 @@
+use crate::environment::MovementGrid;
+use crate::flow_field::FlowFieldFollower;
+use crate::formation::SpeedLimit;
+use crate::hpa_star::HierarchicalRoute;
+use crate::movable::{is_final_leg, Movable, MovementLimits, MovementPath};
+use crate::obstacles::collider_radius;
+use crate::stats::Stats;
+use bevy::prelude::*;
+use bevy_rapier3d::geometry::Collider;
+use std::f32::consts::TAU;
+
+/// Directions and speeds tried for every unit, besides its preferred velocity and standing still
+const SAMPLED_DIRECTIONS: usize = 16;
+const SAMPLED_SPEEDS: [f32; 3] = [0.33, 0.67, 1.0];
+/// Penalty per second of time left before a collision
+const COLLISION_WEIGHT: f32 = 2.0;
+/// Penalty for closing in on a unit which is already touching
+const OVERLAP_WEIGHT: f32 = 10.0;
+/// Small preference for passing on the same side, so units facing each other do not mirror
+/// every move and block one another
+const SIDE_BIAS: f32 = 0.1;
+
+#[derive(Resource)]
+pub struct AvoidanceSettings {
+    /// Seconds ahead in which collisions are avoided
+    pub time_horizon: f32,
+    /// Units further apart are not considered
+    pub neighbour_distance: f32,
+}
+impl Default for AvoidanceSettings {
+    fn default() -> Self {
+        AvoidanceSettings {
+            time_horizon: 2.0,
+            neighbour_distance: 5.0,
+        }
+    }
+}
+/// Unit as seen by the collision avoidance, on the plane of the movement grid
+#[derive(Clone, Copy, Debug)]
+pub struct Agent {
+    pub position: Vec2,
+    pub velocity: Vec2,
+    pub radius: f32,
+    /// Moving agents take half the effort of avoiding each other, parked ones do not move aside
+    pub moving: bool,
+}
+
+/// Time until two discs touch if the first one moves with the relative velocity, None if they
+/// miss each other
+fn time_to_collision(offset: Vec2, relative_velocity: Vec2, radius: f32) -> Option<f32> {
+    let a: f32 = relative_velocity.length_squared();
+    let b: f32 = offset.dot(relative_velocity);
+    let c: f32 = offset.length_squared() - radius * radius;
+    if b >= 0.0 || a <= f32::EPSILON {
+        return None;
+    }
+    let discriminant: f32 = b * b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    Some(((-b - discriminant.sqrt()) / a).max(0.0))
+}
+/// Picks the velocity closest to the preferred one which avoids colliding with the neighbours
+/// within the time horizon, using reciprocal velocity obstacles: towards moving neighbours the
+/// agent only accounts for half of the change in velocity, expecting them to do the rest.
+pub fn avoidance_velocity(
+    agent: &Agent,
+    preferred: Vec2,
+    max_speed: f32,
+    neighbours: &[Agent],
+    time_horizon: f32,
+) -> Vec2 {
+    let mut candidates: Vec<Vec2> = vec![preferred, Vec2::ZERO];
+    for direction in 0..SAMPLED_DIRECTIONS {
+        let angle: f32 = direction as f32 * TAU / SAMPLED_DIRECTIONS as f32;
+        for speed in SAMPLED_SPEEDS {
+            candidates.push(Vec2::from_angle(angle) * speed * max_speed);
+        }
+    }
+    let penalty = |candidate: Vec2| {
+        let mut penalty: f32 =
+            candidate.distance(preferred) + SIDE_BIAS * preferred.perp().dot(candidate).max(0.0);
+        for neighbour in neighbours {
+            let offset: Vec2 = agent.position - neighbour.position;
+            let velocity: Vec2 = if neighbour.moving {
+                2.0 * candidate - agent.velocity
+            } else {
+                candidate
+            };
+            let relative_velocity: Vec2 = velocity - neighbour.velocity;
+            let radius: f32 = agent.radius + neighbour.radius;
+            if offset.length() < radius {
+                let closing_speed: f32 = -offset.normalize_or_zero().dot(relative_velocity);
+                penalty += OVERLAP_WEIGHT * closing_speed.max(0.0);
+            } else if let Some(time) = time_to_collision(offset, relative_velocity, radius)
+                .filter(|&time| time < time_horizon)
+            {
+                penalty += COLLISION_WEIGHT / time.max(f32::EPSILON);
+            }
+        }
+        penalty
+    };
+    candidates
+        .into_iter()
+        .map(|candidate| (penalty(candidate), candidate))
+        .min_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map_or(preferred, |(_, candidate)| candidate)
+}
+
+/// Steers moving units around each other after they followed their paths. Units are pushed
+/// sideways only, their facing and speed stay with the path following.
+pub fn avoid_collisions(
+    mut units: Query<(
+        Entity,
+        &mut Transform,
+        &mut Movable,
+        Option<&Collider>,
+        Option<&MovementPath>,
+        Has<FlowFieldFollower>,
+        Option<&HierarchicalRoute>,
+        Option<&Stats>,
+        Option<&SpeedLimit>,
+    )>,
+    gridmap: Res<MovementGrid>,
+    avoidance_settings: Res<AvoidanceSettings>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    let delta: f32 = time.delta_seconds();
+    if delta <= 0.0 {
+        return;
+    }
+    let agents: Vec<(Entity, Agent)> = units
+        .iter()
+        .map(
+            |(entity, transform, movable, collider, path, follower, ..)| {
+                let facing: Vec3 = transform.rotation * Vec3::Z;
+                let velocity: Vec2 =
+                    Vec2::new(facing.x, facing.z).normalize_or_zero() * movable.speed;
+                (
+                    entity,
+                    Agent {
+                        position: transform.translation.xz(),
+                        velocity,
+                        radius: collider.map_or(0.0, collider_radius),
+                        moving: path.is_some() || follower,
+                    },
+                )
+            },
+        )
+        .collect();
+    for (entity, mut transform, mut movable, _, path, _, route, stats, speed_limit) in
+        units.iter_mut()
+    {
+        let Some(&(_, agent)) = agents.iter().find(|(other, _)| *other == entity) else {
+            continue;
+        };
+        if !agent.moving {
+            continue;
+        }
+        let neighbours: Vec<Agent> = agents
+            .iter()
+            .filter(|(other, neighbour)| {
+                *other != entity
+                    && neighbour.position.distance(agent.position)
+                        < avoidance_settings.neighbour_distance + agent.radius + neighbour.radius
+            })
+            .map(|&(_, neighbour)| neighbour)
+            .collect();
+        if neighbours.is_empty() {
+            continue;
+        }
+        // Units heading for a spot another unit is parked on stop next to it instead
+        if let Some(path) = path.filter(|path| path.path.len() == 1 && is_final_leg(path, route)) {
+            let destination: Vec2 = path.path[0].xy;
+            if neighbours.iter().any(|neighbour| {
+                !neighbour.moving
+                    && neighbour.position.distance(destination) < agent.radius + neighbour.radius
+                    && neighbour.position.distance(agent.position)
+                        < 1.5 * (agent.radius + neighbour.radius)
+            }) {
+                movable.speed = 0.0;
+                commands.entity(entity).remove::<MovementPath>();
+                continue;
+            }
+        }
+        let max_speed: f32 = MovementLimits::new(stats, speed_limit).max_speed;
+        let velocity: Vec2 = avoidance_velocity(
+            &agent,
+            agent.velocity,
+            max_speed,
+            &neighbours,
+            avoidance_settings.time_horizon,
+        );
+        // Only the sideways part of the change is applied, along the heading the path following
+        // stays in charge
+        let side: Vec2 = agent.velocity.normalize_or_zero().perp();
+        let correction: Vec2 = side * side.dot(velocity - agent.velocity) * delta;
+        let position: Vec2 = transform.translation.xz() + correction;
+        // Avoiding units must not be pushed into obstacles
+        if gridmap
+            .cell_at(position)
+            .is_some_and(|cell| gridmap.is_free(cell))
+        {
+            transform.translation.x = position.x;
+            transform.translation.z = position.y;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathfinding::{Heading, PathNode};
+    use std::time::Duration;
+
+    const DELTA: f32 = 0.05;
+
+    fn agent(x: f32, y: f32, velocity: Vec2, moving: bool) -> Agent {
+        Agent {
+            position: Vec2::new(x, y),
+            velocity,
+            radius: 0.5,
+            moving,
+        }
+    }
+
+    #[test]
+    fn avoiding_units_are_only_pushed_sideways() {
+        let mut app: App = App::new();
+        let mut time: Time = Time::default();
+        time.advance_by(Duration::from_secs_f32(DELTA));
+        app.insert_resource(time)
+            .insert_resource(MovementGrid::from_rows(&vec!["..........".to_string(); 10]))
+            .init_resource::<AvoidanceSettings>()
+            .add_systems(Update, avoid_collisions);
+        let start: Vec3 = Vec3::new(5.0, 0.0, 3.0);
+        let moving: Entity = app
+            .world_mut()
+            .spawn((
+                Transform::from_translation(start),
+                Movable { speed: 1.0 },
+                Collider::ball(0.5),
+                MovementPath {
+                    path: vec![PathNode {
+                        xy: Vec2::new(5.0, 9.0),
+                        h: Heading::default(),
+                    }],
+                },
+            ))
+            .id();
+        app.world_mut().spawn((
+            Transform::from_xyz(5.0, 0.0, 4.5),
+            Movable { speed: 0.0 },
+            Collider::ball(0.5),
+        ));
+        app.update();
+        let translation: Vec3 = app.world().get::<Transform>(moving).unwrap().translation;
+        assert_ne!(translation.x, start.x);
+        assert_eq!(translation.z, start.z);
+    }
+
+    #[test]
+    fn free_units_keep_their_velocity() {
+        let unit: Agent = agent(0.0, 0.0, Vec2::Y, true);
+        let far_away: Agent = agent(20.0, 0.0, Vec2::ZERO, false);
+        assert_eq!(avoidance_velocity(&unit, Vec2::Y, 1.0, &[], 2.0), Vec2::Y);
+        assert_eq!(
+            avoidance_velocity(&unit, Vec2::Y, 1.0, &[far_away], 2.0),
+            Vec2::Y
+        );
+    }
+
+    #[test]
+    fn units_on_a_collision_course_pass_each_other() {
+        let mut units: [Agent; 2] = [
+            agent(0.0, 0.0, Vec2::Y, true),
+            agent(0.0, 6.0, Vec2::NEG_Y, true),
+        ];
+        let preferred: [Vec2; 2] = [Vec2::Y, Vec2::NEG_Y];
+        for _ in 0..400 {
+            let velocities: Vec<Vec2> = (0..2)
+                .map(|i| {
+                    let unit: Agent = Agent {
+                        velocity: preferred[i],
+                        ..units[i]
+                    };
+                    let other: Agent = units[1 - i];
+                    avoidance_velocity(&unit, preferred[i], 1.0, &[other], 2.0)
+                })
+                .collect();
+            for (unit, velocity) in units.iter_mut().zip(velocities) {
+                unit.velocity = velocity;
+                unit.position += velocity * DELTA;
+            }
+            assert!(units[0].position.distance(units[1].position) >= 0.9);
+        }
+        // Neither of them got stuck in front of the other
+        assert!(units[0].position.y > 6.0);
+        assert!(units[1].position.y < 0.0);
+    }
+
+    #[test]
+    fn moving_units_go_around_parked_ones() {
+        let mut unit: Agent = agent(0.0, 0.0, Vec2::Y, true);
+        let parked: Agent = agent(0.0, 3.0, Vec2::ZERO, false);
+        for _ in 0..200 {
+            let velocity: Vec2 = avoidance_velocity(&unit, Vec2::Y, 1.0, &[parked], 2.0);
+            unit.velocity = velocity;
+            unit.position += velocity * DELTA;
+            assert!(unit.position.distance(parked.position) >= 0.95);
+        }
+        assert!(unit.position.y > 4.0);
+    }
+}
@@ -2,7 +2,12 @@ use std::collections::HashMap;
 
 use bevy::prelude::*;
 
-use crate::{player_controller::Civilisation, resources::ResourceType};
+use crate::{
+    player_controller::Civilisation,
+    resources::ResourceType,
+    spawner::UnitInformation,
+    stats::{ModifierKind, ModifierSource, StatId, StatModifier, Stats},
+};
 
 pub struct EcoBoni {
     pub resource_boni: HashMap<ResourceType, f32>,
@@ -29,10 +34,34 @@ fn setup_civilisations(mut commands: Commands) {
     );
     commands.insert_resource(civ_boni_map);
 }
+/// Hands the bonuses of a civilisation to every new unit as stat modifiers
+fn apply_civilisation_boni(
+    mut new_units: Query<(&UnitInformation, &mut Stats), Added<Stats>>,
+    civilisation_boni_map: Res<CivilisationBoniMap>,
+) {
+    for (unit_information, mut stats) in new_units.iter_mut() {
+        let Some(civilisation_boni) = civilisation_boni_map
+            .map
+            .get(&unit_information.civilisation)
+        else {
+            continue;
+        };
+        for (resource_type, bonus) in &civilisation_boni.eco_boni.resource_boni {
+            stats.add_modifier(
+                StatId::BonusMiningRate(*resource_type),
+                StatModifier {
+                    source: ModifierSource::Civilisation(unit_information.civilisation),
+                    kind: ModifierKind::Additive(*bonus),
+                },
+            );
+        }
+    }
+}
 pub struct CivilisationPlugin;
 
 impl Plugin for CivilisationPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, setup_civilisations);
+        app.add_systems(Startup, setup_civilisations)
+            .add_systems(Update, apply_civilisation_boni);
     }
 }
@@ -1,3 +1,4 @@
+use crate::obstacles::GridObstacle;
 use crate::resources::{ResourceLevel, ResourceSource, ResourceType};
 use crate::spawner::{UnitSpecification, UnitStats};
 use crate::utils::ShapeTypeSerializable;
@@ -22,10 +23,11 @@ pub struct Environment;
 impl Plugin for Environment {
     fn build(&self, app: &mut App) {
         app.add_plugins(MaterialPlugin::<CustomMaterial>::default())
+            .add_event::<MovementGridChanged>()
             .add_systems(Startup, (environment_setup, setup_movement_grid))
             .insert_resource(MovementGrid {
                 settings: GridSettings {
-                    cell_size: 0.2,
+                    cell_size: CELL_SIZE,
                     grid_width: 1000,
                     grid_height: 1000,
                     xy_offset: Vec2::new(500.0, 500.0),
@@ -44,7 +46,9 @@ pub struct CustomMaterial {
     alpha_mode: AlphaMode,
 }
 
-#[derive(Resource)]
+/// Edge length of a movement grid cell in world units
+pub const CELL_SIZE: f32 = 0.2;
+#[derive(Resource, Clone)]
 pub struct GridSettings {
     pub cell_size: f32,
     pub grid_width: u32,
@@ -52,11 +56,100 @@ pub struct GridSettings {
     pub xy_offset: Vec2,
     pub density: f64, // TODO put into map generation
 }
-#[derive(Resource)]
+/// Sent whenever cells of the movement grid get blocked or freed, covering the changed cells
+#[derive(Event, Clone, Copy, Debug)]
+pub struct MovementGridChanged {
+    pub min: UVec2,
+    pub max: UVec2,
+}
+#[derive(Resource, Clone)]
 pub struct MovementGrid {
     pub settings: GridSettings,
     pub grid: Vec<Vec<u8>>,
 }
+impl MovementGrid {
+    pub fn width(&self) -> usize {
+        self.grid.len()
+    }
+    pub fn height(&self) -> usize {
+        self.grid.first().map_or(0, Vec::len)
+    }
+    /// Cell containing the given point of the xz plane, None if it lies outside of the grid
+    pub fn cell_at(&self, position: Vec2) -> Option<UVec2> {
+        let cell: Vec2 = (position / self.settings.cell_size + self.settings.xy_offset).floor();
+        if cell.x < 0.0
+            || cell.y < 0.0
+            || cell.x >= self.width() as f32
+            || cell.y >= self.height() as f32
+        {
+            return None;
+        }
+        Some(cell.as_uvec2())
+    }
+    /// Cell closest to the given point of the xz plane, None only if the grid is empty
+    pub fn clamped_cell_at(&self, position: Vec2) -> Option<UVec2> {
+        if self.width() == 0 || self.height() == 0 {
+            return None;
+        }
+        let cell: Vec2 = (position / self.settings.cell_size + self.settings.xy_offset).floor();
+        let max: Vec2 = Vec2::new(self.width() as f32, self.height() as f32) - 1.0;
+        Some(cell.clamp(Vec2::ZERO, max).as_uvec2())
+    }
+    /// Position of the cell on the xz plane
+    pub fn cell_position(&self, cell: UVec2) -> Vec2 {
+        (cell.as_vec2() - self.settings.xy_offset) * self.settings.cell_size
+    }
+    /// Centre of the cell on the xz plane, which maps back to the same cell
+    pub fn cell_center(&self, cell: UVec2) -> Vec2 {
+        self.cell_position(cell) + self.settings.cell_size / 2.0
+    }
+    pub fn is_free(&self, cell: UVec2) -> bool {
+        self.grid[cell.x as usize][cell.y as usize] == 0
+    }
+    /// True if every cell within the radius around the position is on the grid and unblocked
+    pub fn is_area_free(&self, position: Vec2, radius: f32) -> bool {
+        let (Some(min), Some(max)) = (
+            self.cell_at(position - Vec2::splat(radius)),
+            self.cell_at(position + Vec2::splat(radius)),
+        ) else {
+            return false;
+        };
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                if !self.is_free(UVec2 { x, y }) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+#[cfg(test)]
+impl MovementGrid {
+    /// Grid with unit sized cells and no offset, so cell and world coordinates match.
+    /// Rows are given from y = 0 upwards, `#` marks a blocked cell.
+    pub fn from_rows(rows: &[impl AsRef<str>]) -> Self {
+        let width: usize = rows.first().map_or(0, |row| row.as_ref().len());
+        let mut grid: Vec<Vec<u8>> = vec![vec![0; rows.len()]; width];
+        for (y, row) in rows.iter().enumerate() {
+            for (x, cell) in row.as_ref().chars().enumerate() {
+                if cell == '#' {
+                    grid[x][y] = 1;
+                }
+            }
+        }
+        MovementGrid {
+            settings: GridSettings {
+                cell_size: 1.0,
+                grid_width: width as u32,
+                grid_height: rows.len() as u32,
+                xy_offset: Vec2::ZERO,
+                density: 0.0,
+            },
+            grid,
+        }
+    }
+}
 
 /// The Material trait is very configurable, but comes with sensible defaults for all methods.
 /// You only need to implement functions for features that need non-default behavior. See the Material api docs for details!
@@ -132,6 +225,7 @@ pub fn environment_setup(
             GravityScale(0.0),
             RenderLayers::layer(RenderLayerMap::Main as usize),
             Collider::ball(1.0),
+            GridObstacle,
             ResourceSource,
             ResourceLevel {
                 resource_type: ResourceType::Plotanium,
@@ -147,12 +241,17 @@ pub fn environment_setup(
             icon_path: "".to_owned(),
             unit_name: "Asteroid".to_owned(),
             movable: true,
+            collector: false,
+            produces: Vec::new(),
             shape: ShapeTypeSerializable(bevy_rapier3d::rapier::prelude::ShapeType::Ball),
             dimensions: Vec3::splat(1.0),
+            border_radius: 0.0,
+            compound: Vec::new(),
             prescaling: 1.0,
             base_stats: UnitStats(Vec::new()),
             unit_info: "Asteroid. Rich in Plotanium".into(),
             unit_cost: HashMap::new(),
+            build_time: 0.0,
         },
     ));
 
@@ -180,16 +279,21 @@ pub fn environment_setup(
             icon_path: "".to_owned(),
             unit_name: "Sun".to_owned(),
             movable: true,
+            collector: false,
+            produces: Vec::new(),
             shape: ShapeTypeSerializable(bevy_rapier3d::rapier::prelude::ShapeType::Ball),
             dimensions: Vec3::splat(1.0),
+            border_radius: 0.0,
+            compound: Vec::new(),
             prescaling: 1.0,
             base_stats: UnitStats(Vec::new()),
             unit_info: "The central star of the system. Don't come to close".into(),
             unit_cost: HashMap::new(),
+            build_time: 0.0,
         },
     ));
 }
-fn setup_movement_grid(mut movement_grid: ResMut<MovementGrid>) {
+pub fn setup_movement_grid(mut movement_grid: ResMut<MovementGrid>) {
     for i in 0..movement_grid.settings.grid_width as usize {
         movement_grid.grid.push(Vec::new());
         for _ in 0..movement_grid.settings.grid_height as usize {
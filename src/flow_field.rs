@@ -0,0 +1,232 @@
+use crate::environment::MovementGrid;
+use crate::formation::{formation_slots, SelectedFormation, SpeedLimit, SLOT_SPACING};
+use crate::hpa_star::{area_distances, area_index, grid_steps, CLUSTER_SIZE};
+use crate::movable::{halt, MoveCommand, MovementLimits};
+use crate::pathfinding::{calculate_heading, PathNode};
+use crate::stats::Stats;
+use bevy::prelude::*;
+use bevy::tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task};
+
+/// Cells the flow field reaches beyond the units and the target, leaving room for detours
+const FLOW_FIELD_MARGIN: u32 = CLUSTER_SIZE;
+
+/// Move order for several units to the same target, sharing a single flow field
+#[derive(Event)]
+pub struct GroupMoveCommand {
+    pub units: Vec<Entity>,
+    pub target: Vec2,
+}
+/// Directions towards one target for every cell of an area of the movement grid
+#[derive(Component)]
+pub struct FlowField {
+    pub target: Vec2,
+    pub goal: UVec2,
+    /// Distance to the target from which units leave the field for their own slot
+    pub arrival_radius: f32,
+    min: UVec2,
+    size: UVec2,
+    /// Path cost from each cell to the goal, None where the goal can not be reached
+    integration: Vec<Option<i32>>,
+    /// Cell to move to next from each cell
+    directions: Vec<Option<UVec2>>,
+}
+/// Flow field which is still being computed in the background, replaced by the field once done
+#[derive(Component)]
+pub struct FlowFieldTask(Task<FlowField>);
+/// Unit moving along a flow field entity, with the cell and node it is currently heading for
+#[derive(Component)]
+pub struct FlowFieldFollower {
+    pub field: Entity,
+    /// Final position of the unit within the group's formation
+    pub slot: Vec2,
+    pub next: Option<(UVec2, PathNode)>,
+}
+impl FlowField {
+    /// Builds the field for the area from `min` up to, but excluding, `max`
+    pub fn new(grid: &MovementGrid, target: Vec2, goal: UVec2, min: UVec2, max: UVec2) -> Self {
+        let size: UVec2 = max - min;
+        let mut flow_field: FlowField = FlowField {
+            target,
+            goal,
+            arrival_radius: 0.0,
+            min,
+            size,
+            integration: area_distances(grid, goal, min, max).costs,
+            directions: vec![None; (size.x * size.y) as usize],
+        };
+        for index in 0..flow_field.integration.len() {
+            let cell: UVec2 = min + UVec2::new(index as u32 / size.y, index as u32 % size.y);
+            if cell == goal || flow_field.integration[index].is_none() {
+                continue;
+            }
+            flow_field.directions[index] = grid_steps(grid, cell)
+                .filter_map(|(neighbour, step)| {
+                    flow_field
+                        .integration(neighbour)
+                        .map(|cost| (cost + step, neighbour))
+                })
+                .min_by_key(|&(cost, _)| cost)
+                .map(|(_, neighbour)| neighbour);
+        }
+        flow_field
+    }
+    fn index(&self, cell: UVec2) -> Option<usize> {
+        area_index(self.min, self.min + self.size, cell)
+    }
+    pub fn integration(&self, cell: UVec2) -> Option<i32> {
+        self.index(cell).and_then(|index| self.integration[index])
+    }
+    pub fn direction(&self, cell: UVec2) -> Option<UVec2> {
+        self.index(cell).and_then(|index| self.directions[index])
+    }
+    /// Next cell and path node from the given cell
+    pub fn step(&self, grid: &MovementGrid, cell: UVec2) -> Option<(UVec2, PathNode)> {
+        let next: UVec2 = self.direction(cell)?;
+        Some((
+            next,
+            PathNode {
+                xy: grid.cell_center(next),
+                h: calculate_heading(&cell, &next),
+            },
+        ))
+    }
+}
+
+/// Starts computing one flow field per group order, spanning the units and the target. Every
+/// unit gets a slot of the selected formation, which it heads for once it is close to the
+/// target. Large fields take a while, so they are built on the async compute pool.
+pub fn start_group_moves(
+    mut group_moves: EventReader<GroupMoveCommand>,
+    units: Query<(Entity, &Transform, Option<&Stats>)>,
+    gridmap: Res<MovementGrid>,
+    selected_formation: Res<SelectedFormation>,
+    mut commands: Commands,
+) {
+    for group_move in group_moves.read() {
+        let target: Vec2 = group_move.target;
+        let mut members: Vec<Entity> = Vec::new();
+        let mut positions: Vec<Vec2> = Vec::new();
+        let mut slowest: Option<f32> = None;
+        for (entity, transform, stats) in units.iter_many(&group_move.units) {
+            members.push(entity);
+            positions.push(transform.translation.xz());
+            // Members without a speed stat move at the default speed
+            let speed: f32 = MovementLimits::new(stats, None).max_speed;
+            slowest = Some(slowest.map_or(speed, |slowest| slowest.min(speed)));
+        }
+        let slots: Vec<Vec2> = formation_slots(selected_formation.0, &positions, target);
+        for &unit in &members {
+            halt(&mut commands.entity(unit));
+            if let Some(speed) = slowest {
+                commands.entity(unit).insert(SpeedLimit(speed));
+            }
+        }
+        let Some(goal) = gridmap
+            .cell_at(target)
+            .filter(|&goal| gridmap.is_free(goal))
+        else {
+            // The single unit pathfinding deals with invalid targets
+            for (&unit, &slot) in members.iter().zip(&slots) {
+                commands.entity(unit).insert(MoveCommand { target: slot });
+            }
+            continue;
+        };
+        let mut min: UVec2 = goal;
+        let mut max: UVec2 = goal;
+        for &position in &positions {
+            if let Some(cell) = gridmap.cell_at(position) {
+                min = min.min(cell);
+                max = max.max(cell);
+            }
+        }
+        let min: UVec2 = min.saturating_sub(UVec2::splat(FLOW_FIELD_MARGIN));
+        let max: UVec2 = (max + FLOW_FIELD_MARGIN + 1)
+            .min(UVec2::new(gridmap.width() as u32, gridmap.height() as u32));
+        let arrival_radius: f32 = slots
+            .iter()
+            .map(|slot| slot.distance(target))
+            .fold(0.0, f32::max)
+            + SLOT_SPACING;
+        let grid: MovementGrid = gridmap.clone();
+        let task: Task<FlowField> = AsyncComputeTaskPool::get().spawn(async move {
+            let mut flow_field: FlowField = FlowField::new(&grid, target, goal, min, max);
+            flow_field.arrival_radius = arrival_radius;
+            flow_field
+        });
+        let field: Entity = commands.spawn(FlowFieldTask(task)).id();
+        for (&unit, &slot) in members.iter().zip(&slots) {
+            commands.entity(unit).insert(FlowFieldFollower {
+                field,
+                slot,
+                next: None,
+            });
+        }
+    }
+}
+/// Swaps finished background computations for their flow fields
+pub fn finish_flow_fields(mut tasks: Query<(Entity, &mut FlowFieldTask)>, mut commands: Commands) {
+    for (entity, mut task) in tasks.iter_mut() {
+        if let Some(flow_field) = block_on(future::poll_once(&mut task.0)) {
+            commands
+                .entity(entity)
+                .remove::<FlowFieldTask>()
+                .insert(flow_field);
+        }
+    }
+}
+/// Despawns fields nobody follows anymore, which also cancels unfinished computations
+pub fn remove_unused_flow_fields(
+    flow_fields: Query<Entity, Or<(With<FlowField>, With<FlowFieldTask>)>>,
+    followers: Query<&FlowFieldFollower>,
+    mut commands: Commands,
+) {
+    for field in flow_fields.iter() {
+        if !followers.iter().any(|follower| follower.field == field) {
+            commands.entity(field).despawn();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow_field(grid: &MovementGrid, goal: UVec2) -> FlowField {
+        let max: UVec2 = UVec2::new(grid.width() as u32, grid.height() as u32);
+        FlowField::new(grid, goal.as_vec2(), goal, UVec2::ZERO, max)
+    }
+
+    #[test]
+    fn every_cell_leads_to_the_goal() {
+        let grid: MovementGrid = MovementGrid::from_rows(&[".....", ".###.", ".....", "....."]);
+        let goal: UVec2 = UVec2::new(2, 0);
+        let field: FlowField = flow_field(&grid, goal);
+        for x in 0..5 {
+            for y in 0..4 {
+                let mut cell: UVec2 = UVec2 { x, y };
+                if !grid.is_free(cell) {
+                    continue;
+                }
+                let mut steps: u32 = 0;
+                while cell != goal {
+                    let next: UVec2 = field.direction(cell).unwrap();
+                    assert!(grid.is_free(next));
+                    assert!(field.integration(next) < field.integration(cell));
+                    cell = next;
+                    steps += 1;
+                    assert!(steps < 20);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cut_off_cells_have_no_direction() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["...", "###", "..."]);
+        let field: FlowField = flow_field(&grid, UVec2::new(0, 0));
+        assert_eq!(field.direction(UVec2::new(1, 2)), None);
+        assert_eq!(field.integration(UVec2::new(1, 2)), None);
+        assert_eq!(field.direction(UVec2::new(5, 5)), None);
+        assert_eq!(field.integration(UVec2::new(0, 0)), Some(0));
+    }
+}
@@ -0,0 +1,137 @@
+use bevy::prelude::*;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+/// Distance between neighbouring slots of a formation
+pub const SLOT_SPACING: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, EnumIter)]
+pub enum FormationShape {
+    #[default]
+    Line,
+    Wedge,
+    Box,
+    /// Units keep their positions relative to each other
+    KeepOffsets,
+}
+/// Formation used for the next group order
+#[derive(Resource, Default)]
+pub struct SelectedFormation(pub FormationShape);
+/// Caps the speed of a unit, so a group travels at the pace of its slowest member
+#[derive(Component)]
+pub struct SpeedLimit(pub f32);
+
+pub fn select_formation(
+    key_input: Res<ButtonInput<KeyCode>>,
+    mut selected_formation: ResMut<SelectedFormation>,
+) {
+    if key_input.just_pressed(KeyCode::KeyF) {
+        let shapes: Vec<FormationShape> = FormationShape::iter().collect();
+        let index: usize = shapes
+            .iter()
+            .position(|&shape| shape == selected_formation.0)
+            .unwrap_or(0);
+        selected_formation.0 = shapes[(index + 1) % shapes.len()];
+        info!("Formation: {:?}", selected_formation.0);
+    }
+}
+/// Gives every unit its own slot around the target. The formation faces from the centre of the
+/// units towards the target, and slots go to the units closest to them to avoid crossing paths.
+pub fn formation_slots(shape: FormationShape, positions: &[Vec2], target: Vec2) -> Vec<Vec2> {
+    if positions.is_empty() {
+        return Vec::new();
+    }
+    let count: usize = positions.len();
+    let centre: Vec2 = positions.iter().sum::<Vec2>() / count as f32;
+    let forward: Vec2 = (target - centre).try_normalize().unwrap_or(Vec2::Y);
+    let right: Vec2 = Vec2::new(forward.y, -forward.x);
+    let offsets: Vec<Vec2> = match shape {
+        FormationShape::Line => (0..count)
+            .map(|i| right * (i as f32 - (count - 1) as f32 / 2.0) * SLOT_SPACING)
+            .collect(),
+        FormationShape::Wedge => (0..count)
+            .map(|i| {
+                let row: f32 = ((i + 1) / 2) as f32;
+                let side: f32 = if i % 2 == 0 { 1.0 } else { -1.0 };
+                (right * side - forward) * row * SLOT_SPACING
+            })
+            .collect(),
+        FormationShape::Box => {
+            let columns: usize = (count as f32).sqrt().ceil() as usize;
+            let rows: usize = count.div_ceil(columns);
+            (0..count)
+                .map(|i| {
+                    let column: f32 = (i % columns) as f32 - (columns - 1) as f32 / 2.0;
+                    let row: f32 = (i / columns) as f32 - (rows - 1) as f32 / 2.0;
+                    (right * column - forward * row) * SLOT_SPACING
+                })
+                .collect()
+        }
+        FormationShape::KeepOffsets => {
+            return positions
+                .iter()
+                .map(|&position| target + position - centre)
+                .collect()
+        }
+    };
+    let mut slots: Vec<Vec2> = vec![target; count];
+    let mut unassigned: Vec<usize> = (0..count).collect();
+    for offset in offsets {
+        let Some((index, &unit)) = unassigned.iter().enumerate().min_by(|(_, &a), (_, &b)| {
+            (positions[a] - centre)
+                .distance_squared(offset)
+                .total_cmp(&(positions[b] - centre).distance_squared(offset))
+        }) else {
+            break;
+        };
+        slots[unit] = target + offset;
+        unassigned.swap_remove(index);
+    }
+    slots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn units() -> Vec<Vec2> {
+        vec![
+            Vec2::new(0.0, 0.0),
+            Vec2::new(1.0, 0.0),
+            Vec2::new(2.0, 0.0),
+            Vec2::new(0.0, 1.0),
+            Vec2::new(1.0, 1.0),
+        ]
+    }
+
+    #[test]
+    fn slots_are_distinct() {
+        for shape in FormationShape::iter() {
+            let slots: Vec<Vec2> = formation_slots(shape, &units(), Vec2::new(20.0, 30.0));
+            assert_eq!(slots.len(), units().len());
+            for (i, a) in slots.iter().enumerate() {
+                for b in &slots[i + 1..] {
+                    assert!(a.distance(*b) >= 1.0, "{:?} overlaps at {}", shape, a);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn line_is_perpendicular_to_travel_direction() {
+        let target: Vec2 = Vec2::new(1.0, 40.0);
+        let slots: Vec<Vec2> = formation_slots(FormationShape::Line, &units(), target);
+        let forward: Vec2 = Vec2::Y;
+        for slot in slots {
+            assert!((slot - target).dot(forward).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn relative_offsets_are_kept() {
+        let slots: Vec<Vec2> =
+            formation_slots(FormationShape::KeepOffsets, &units(), Vec2::new(10.0, 10.0));
+        assert_eq!(slots[1] - slots[0], Vec2::new(1.0, 0.0));
+        assert_eq!(slots[3] - slots[0], Vec2::new(0.0, 1.0));
+    }
+}
@@ -0,0 +1,522 @@
+use crate::a_star::{AStarParams, PathFailed};
+use crate::environment::{MovementGrid, MovementGridChanged};
+use crate::formation::SpeedLimit;
+use crate::movable::{current_heading, MoveCommand, MovementLimits, MovementPath};
+use crate::pathfinding::{check_path_width, PathError, PathSearch, DISTANCE_FACTOR};
+use crate::stats::Stats;
+use bevy::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// Cells per side of a cluster
+pub const CLUSTER_SIZE: u32 = 50;
+/// Free border sections wider than this get an entrance at both ends instead of one in the middle
+const MAX_ENTRANCE_WIDTH: usize = 6;
+/// Offsets of the straight and diagonal neighbours of a cell
+const GRID_STEPS: [IVec2; 8] = [
+    IVec2::new(1, 0),
+    IVec2::new(-1, 0),
+    IVec2::new(0, 1),
+    IVec2::new(0, -1),
+    IVec2::new(1, 1),
+    IVec2::new(1, -1),
+    IVec2::new(-1, 1),
+    IVec2::new(-1, -1),
+];
+
+#[derive(Clone, Copy, Debug)]
+struct Edge {
+    to: UVec2,
+    cost: i32,
+    /// Crosses the border into a neighbouring cluster
+    inter: bool,
+}
+/// Abstraction of the movement grid into square clusters. Paths are planned over the entrances
+/// between neighbouring clusters first and refined on the grid one cluster at a time.
+#[derive(Resource)]
+pub struct ClusterGraph {
+    cluster_size: u32,
+    clusters: UVec2,
+    entrances: HashMap<UVec2, Vec<UVec2>>,
+    edges: HashMap<UVec2, Vec<Edge>>,
+}
+/// Coarse route of a unit, one waypoint per cluster it enters. The next waypoint is the last one.
+#[derive(Component)]
+pub struct HierarchicalRoute {
+    pub waypoints: Vec<UVec2>,
+}
+impl ClusterGraph {
+    pub fn new(grid: &MovementGrid, cluster_size: u32) -> Self {
+        let mut graph: ClusterGraph = ClusterGraph {
+            cluster_size,
+            clusters: UVec2::new(
+                (grid.width() as u32).div_ceil(cluster_size),
+                (grid.height() as u32).div_ceil(cluster_size),
+            ),
+            entrances: HashMap::new(),
+            edges: HashMap::new(),
+        };
+        let all_clusters: HashSet<UVec2> = (0..graph.clusters.x)
+            .flat_map(|x| (0..graph.clusters.y).map(move |y| UVec2 { x, y }))
+            .collect();
+        graph.rebuild(grid, &all_clusters);
+        graph
+    }
+    pub fn cluster_of(&self, cell: UVec2) -> UVec2 {
+        cell / self.cluster_size
+    }
+    /// First cell and the cell past the last one of the cluster
+    fn bounds(&self, grid: &MovementGrid, cluster: UVec2) -> (UVec2, UVec2) {
+        let min: UVec2 = cluster * self.cluster_size;
+        let max: UVec2 =
+            (min + self.cluster_size).min(UVec2::new(grid.width() as u32, grid.height() as u32));
+        (min, max)
+    }
+    /// Pairs of cells facing each other across the border to the next cluster along the axis
+    fn border_pairs(
+        &self,
+        grid: &MovementGrid,
+        cluster: UVec2,
+        axis: usize,
+    ) -> Vec<(UVec2, UVec2)> {
+        if cluster[axis] + 1 >= self.clusters[axis] {
+            return Vec::new();
+        }
+        let (min, max) = self.bounds(grid, cluster);
+        let along: usize = 1 - axis;
+        (min[along]..max[along])
+            .map(|position| {
+                let mut inside: UVec2 = UVec2::ZERO;
+                inside[axis] = max[axis] - 1;
+                inside[along] = position;
+                let mut outside: UVec2 = inside;
+                outside[axis] += 1;
+                (inside, outside)
+            })
+            .collect()
+    }
+    /// Recomputes the entrances on the borders of the given clusters and the paths through them
+    /// and their neighbours
+    pub fn rebuild(&mut self, grid: &MovementGrid, clusters: &HashSet<UVec2>) {
+        // Borders are identified by the cluster on their lower side and the axis they cross
+        let mut borders: HashSet<(UVec2, usize)> = HashSet::new();
+        let mut affected: HashSet<UVec2> = clusters.clone();
+        for &cluster in clusters {
+            for axis in 0..2 {
+                borders.insert((cluster, axis));
+                let mut next: UVec2 = cluster;
+                next[axis] += 1;
+                if next[axis] < self.clusters[axis] {
+                    affected.insert(next);
+                }
+                if cluster[axis] > 0 {
+                    let mut previous: UVec2 = cluster;
+                    previous[axis] -= 1;
+                    borders.insert((previous, axis));
+                    affected.insert(previous);
+                }
+            }
+        }
+        for (cluster, axis) in borders {
+            let pairs: Vec<(UVec2, UVec2)> = self.border_pairs(grid, cluster, axis);
+            for &(inside, outside) in &pairs {
+                self.remove_edge(inside, outside);
+                self.remove_edge(outside, inside);
+            }
+            for section in pairs
+                .split(|&(inside, outside)| !grid.is_free(inside) || !grid.is_free(outside))
+                .filter(|section| !section.is_empty())
+            {
+                let entrances: Vec<(UVec2, UVec2)> = if section.len() > MAX_ENTRANCE_WIDTH {
+                    vec![section[0], section[section.len() - 1]]
+                } else {
+                    vec![section[section.len() / 2]]
+                };
+                for (inside, outside) in entrances {
+                    self.add_inter_edge(inside, outside);
+                    self.add_inter_edge(outside, inside);
+                }
+            }
+        }
+        for cluster in affected {
+            self.connect_entrances(grid, cluster);
+        }
+    }
+    fn remove_edge(&mut self, from: UVec2, to: UVec2) {
+        if let Some(edges) = self.edges.get_mut(&from) {
+            edges.retain(|edge| edge.to != to);
+        }
+    }
+    fn add_inter_edge(&mut self, from: UVec2, to: UVec2) {
+        self.edges.entry(from).or_default().push(Edge {
+            to,
+            cost: step_cost(from, to),
+            inter: true,
+        });
+    }
+    /// Connects the entrances of a cluster with the cost of the shortest path between them
+    /// which stays inside the cluster
+    fn connect_entrances(&mut self, grid: &MovementGrid, cluster: UVec2) {
+        let (min, max) = self.bounds(grid, cluster);
+        let mut entrances: Vec<UVec2> = Vec::new();
+        for x in min.x..max.x {
+            for y in min.y..max.y {
+                let cell: UVec2 = UVec2 { x, y };
+                let Some(edges) = self.edges.get_mut(&cell) else {
+                    continue;
+                };
+                edges.retain(|edge| edge.inter);
+                if edges.is_empty() {
+                    self.edges.remove(&cell);
+                } else {
+                    entrances.push(cell);
+                }
+            }
+        }
+        for &entrance in &entrances {
+            let distances: AreaDistances = area_distances(grid, entrance, min, max);
+            let intra_edges: Vec<Edge> = entrances
+                .iter()
+                .filter(|&&other| other != entrance)
+                .filter_map(|&other| {
+                    distances.get(other).map(|cost| Edge {
+                        to: other,
+                        cost,
+                        inter: false,
+                    })
+                })
+                .collect();
+            self.edges.entry(entrance).or_default().extend(intra_edges);
+        }
+        self.entrances.insert(cluster, entrances);
+    }
+    /// Waypoints towards the goal, none when it lies in the start cluster. Fails when the
+    /// cluster graph already shows that the goal can not be reached from the start.
+    pub fn route(
+        &self,
+        grid: &MovementGrid,
+        start: UVec2,
+        goal: UVec2,
+    ) -> Result<Vec<UVec2>, PathError> {
+        if self.cluster_of(start) == self.cluster_of(goal) {
+            return Ok(Vec::new());
+        }
+        self.find_route(grid, start, goal)
+            .ok_or(PathError::Unreachable(goal))
+    }
+    /// Plans a coarse route over the cluster entrances. Returns the cell where the route enters
+    /// each cluster on its way, followed by the goal.
+    pub fn find_route(&self, grid: &MovementGrid, start: UVec2, goal: UVec2) -> Option<Vec<UVec2>> {
+        let start_cluster: UVec2 = self.cluster_of(start);
+        let goal_cluster: UVec2 = self.cluster_of(goal);
+        let (min, max) = self.bounds(grid, start_cluster);
+        let from_start: AreaDistances = area_distances(grid, start, min, max);
+        let (min, max) = self.bounds(grid, goal_cluster);
+        let to_goal: AreaDistances = area_distances(grid, goal, min, max);
+
+        let mut g_scores: HashMap<UVec2, i32> = HashMap::from([(start, 0)]);
+        let mut came_from: HashMap<UVec2, UVec2> = HashMap::new();
+        let mut closed_set: HashSet<UVec2> = HashSet::new();
+        let mut open_set: BinaryHeap<Reverse<(i32, u32, u32)>> =
+            BinaryHeap::from([Reverse((step_cost(start, goal), start.x, start.y))]);
+        while let Some(Reverse((_, x, y))) = open_set.pop() {
+            let current: UVec2 = UVec2 { x, y };
+            if !closed_set.insert(current) {
+                continue;
+            }
+            if current == goal {
+                return Some(self.entry_waypoints(&came_from, goal));
+            }
+            let mut successors: Vec<Edge> = self.edges.get(&current).cloned().unwrap_or_default();
+            if current == start {
+                successors.extend(
+                    self.entrances
+                        .get(&start_cluster)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|&entrance| {
+                            from_start.get(entrance).map(|cost| Edge {
+                                to: entrance,
+                                cost,
+                                inter: false,
+                            })
+                        }),
+                );
+            }
+            if self.cluster_of(current) == goal_cluster {
+                if let Some(cost) = to_goal.get(current) {
+                    successors.push(Edge {
+                        to: goal,
+                        cost,
+                        inter: false,
+                    });
+                }
+            }
+            let g_score: i32 = g_scores[&current];
+            for edge in successors {
+                if closed_set.contains(&edge.to) {
+                    continue;
+                }
+                let tentative_g_score: i32 = g_score + edge.cost;
+                if g_scores
+                    .get(&edge.to)
+                    .is_some_and(|&known| known <= tentative_g_score)
+                {
+                    continue;
+                }
+                g_scores.insert(edge.to, tentative_g_score);
+                came_from.insert(edge.to, current);
+                open_set.push(Reverse((
+                    tentative_g_score + step_cost(edge.to, goal),
+                    edge.to.x,
+                    edge.to.y,
+                )));
+            }
+        }
+        None
+    }
+    fn entry_waypoints(&self, came_from: &HashMap<UVec2, UVec2>, goal: UVec2) -> Vec<UVec2> {
+        let mut route: Vec<UVec2> = vec![goal];
+        let mut current: UVec2 = goal;
+        while let Some(&previous) = came_from.get(&current) {
+            route.push(previous);
+            current = previous;
+        }
+        route.reverse();
+        let mut waypoints: Vec<UVec2> = route
+            .windows(2)
+            .filter(|cells| self.cluster_of(cells[0]) != self.cluster_of(cells[1]))
+            .map(|cells| cells[1])
+            .collect();
+        if waypoints.last() != Some(&goal) {
+            waypoints.push(goal);
+        }
+        waypoints
+    }
+}
+pub fn step_cost(from: UVec2, to: UVec2) -> i32 {
+    (from.as_vec2().distance(to.as_vec2()) * DISTANCE_FACTOR) as i32
+}
+/// Free straight and diagonal neighbours of a cell with the cost of stepping there. Like
+/// `get_neighbours`, diagonal steps need one of the two cells beside them to be free.
+pub fn grid_steps(grid: &MovementGrid, cell: UVec2) -> impl Iterator<Item = (UVec2, i32)> + '_ {
+    let size: IVec2 = IVec2::new(grid.width() as i32, grid.height() as i32);
+    GRID_STEPS.iter().filter_map(move |&offset| {
+        let next: IVec2 = cell.as_ivec2() + offset;
+        if next.cmplt(IVec2::ZERO).any() || next.cmpge(size).any() {
+            return None;
+        }
+        let next: UVec2 = next.as_uvec2();
+        (grid.is_free(next) && check_path_width(cell, next, grid))
+            .then(|| (next, step_cost(cell, next)))
+    })
+}
+/// Position of a cell in the column by column storage of the area from `min` up to, but
+/// excluding, `max`
+pub fn area_index(min: UVec2, max: UVec2, cell: UVec2) -> Option<usize> {
+    if cell.cmplt(min).any() || cell.cmpge(max).any() {
+        return None;
+    }
+    let local: UVec2 = cell - min;
+    Some((local.x * (max.y - min.y) + local.y) as usize)
+}
+/// Costs of the shortest paths from one cell to the cells of an area, None where the cell can
+/// not be reached without leaving the area
+pub struct AreaDistances {
+    pub min: UVec2,
+    pub max: UVec2,
+    pub costs: Vec<Option<i32>>,
+}
+impl AreaDistances {
+    pub fn get(&self, cell: UVec2) -> Option<i32> {
+        area_index(self.min, self.max, cell).and_then(|index| self.costs[index])
+    }
+}
+/// Dijkstra search over straight and diagonal steps from the cell through the area
+pub fn area_distances(grid: &MovementGrid, from: UVec2, min: UVec2, max: UVec2) -> AreaDistances {
+    let size: UVec2 = max.max(min) - min;
+    let mut costs: Vec<Option<i32>> = vec![None; (size.x * size.y) as usize];
+    let mut open_set: BinaryHeap<Reverse<(i32, u32, u32)>> = BinaryHeap::new();
+    if let Some(index) = area_index(min, max, from) {
+        costs[index] = Some(0);
+        open_set.push(Reverse((0, from.x, from.y)));
+    }
+    while let Some(Reverse((cost, x, y))) = open_set.pop() {
+        let current: UVec2 = UVec2 { x, y };
+        if area_index(min, max, current)
+            .and_then(|index| costs[index])
+            .is_some_and(|known| known < cost)
+        {
+            continue;
+        }
+        for (cell, step) in grid_steps(grid, current) {
+            let Some(index) = area_index(min, max, cell) else {
+                continue;
+            };
+            let new_cost: i32 = cost + step;
+            if costs[index].is_some_and(|known| known <= new_cost) {
+                continue;
+            }
+            costs[index] = Some(new_cost);
+            open_set.push(Reverse((new_cost, cell.x, cell.y)));
+        }
+    }
+    AreaDistances { min, max, costs }
+}
+
+pub fn build_cluster_graph(gridmap: Res<MovementGrid>, mut commands: Commands) {
+    commands.insert_resource(ClusterGraph::new(&gridmap, CLUSTER_SIZE));
+}
+/// Rebuilds only the clusters touched by changed cells
+pub fn update_cluster_graph(
+    mut grid_changes: EventReader<MovementGridChanged>,
+    gridmap: Res<MovementGrid>,
+    mut cluster_graph: ResMut<ClusterGraph>,
+) {
+    let mut changed_clusters: HashSet<UVec2> = HashSet::new();
+    for change in grid_changes.read() {
+        let min: UVec2 = cluster_graph.cluster_of(change.min);
+        let max: UVec2 = cluster_graph
+            .cluster_of(change.max)
+            .min(cluster_graph.clusters - 1);
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                changed_clusters.insert(UVec2 { x, y });
+            }
+        }
+    }
+    if !changed_clusters.is_empty() {
+        cluster_graph.rebuild(&gridmap, &changed_clusters);
+    }
+}
+/// Starts the search for the next section of a coarse route once the previous one is travelled
+pub fn refine_route(
+    mut routes: Query<
+        (
+            Entity,
+            &Transform,
+            &mut HierarchicalRoute,
+            Option<&Stats>,
+            Option<&SpeedLimit>,
+        ),
+        (
+            Without<MovementPath>,
+            Without<AStarParams>,
+            Without<MoveCommand>,
+        ),
+    >,
+    gridmap: Res<MovementGrid>,
+    mut path_failures: EventWriter<PathFailed>,
+    mut commands: Commands,
+) {
+    for (entity, transform, mut route, stats, speed_limit) in routes.iter_mut() {
+        let goal: Option<UVec2> = route.waypoints.first().copied();
+        let (Some(waypoint), Some(position)) = (
+            route.waypoints.pop(),
+            gridmap.cell_at(transform.translation.xz()),
+        ) else {
+            commands.entity(entity).remove::<HierarchicalRoute>();
+            continue;
+        };
+        let turning_radius: f32 = MovementLimits::new(stats, speed_limit).turning_radius(&gridmap);
+        match PathSearch::new(
+            &gridmap,
+            position,
+            current_heading(transform),
+            waypoint,
+            turning_radius,
+        ) {
+            Ok(search) => {
+                commands.entity(entity).insert(AStarParams { search });
+            }
+            Err(error) => {
+                warn!("{}", error);
+                path_failures.send(PathFailed {
+                    entity,
+                    target: gridmap.cell_center(goal.unwrap_or(waypoint)),
+                    error,
+                });
+                commands.entity(entity).remove::<HierarchicalRoute>();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_grid(width: usize, height: usize) -> MovementGrid {
+        MovementGrid::from_rows(&vec![".".repeat(width); height])
+    }
+
+    #[test]
+    fn route_enters_every_cluster_on_the_way() {
+        let grid: MovementGrid = open_grid(30, 10);
+        let graph: ClusterGraph = ClusterGraph::new(&grid, 10);
+        let route: Vec<UVec2> = graph
+            .find_route(&grid, UVec2::new(1, 5), UVec2::new(28, 5))
+            .unwrap();
+        let clusters: Vec<UVec2> = route.iter().map(|&cell| graph.cluster_of(cell)).collect();
+        assert_eq!(
+            clusters,
+            vec![UVec2::new(1, 0), UVec2::new(2, 0), UVec2::new(2, 0)]
+        );
+        assert_eq!(route.last(), Some(&UVec2::new(28, 5)));
+    }
+
+    #[test]
+    fn walled_off_goal_has_no_route() {
+        let mut grid: MovementGrid = open_grid(20, 10);
+        for y in 0..10 {
+            grid.grid[10][y] = 1;
+        }
+        let graph: ClusterGraph = ClusterGraph::new(&grid, 10);
+        assert_eq!(
+            graph.find_route(&grid, UVec2::new(2, 2), UVec2::new(15, 2)),
+            None
+        );
+    }
+
+    #[test]
+    fn goals_in_walled_off_clusters_are_unreachable() {
+        let mut grid: MovementGrid = open_grid(30, 10);
+        for y in 0..10 {
+            grid.grid[10][y] = 1;
+            grid.grid[19][y] = 1;
+        }
+        let graph: ClusterGraph = ClusterGraph::new(&grid, 10);
+        let goal: UVec2 = UVec2::new(25, 2);
+        assert_eq!(
+            graph.route(&grid, UVec2::new(2, 2), goal),
+            Err(PathError::Unreachable(goal))
+        );
+        assert_eq!(
+            graph.route(&grid, UVec2::new(12, 2), UVec2::new(15, 8)),
+            Ok(Vec::new())
+        );
+    }
+
+    #[test]
+    fn rebuild_picks_up_changed_cells() {
+        let mut grid: MovementGrid = open_grid(20, 10);
+        let mut graph: ClusterGraph = ClusterGraph::new(&grid, 10);
+        assert!(graph
+            .find_route(&grid, UVec2::new(2, 2), UVec2::new(15, 2))
+            .is_some());
+        for y in 0..10 {
+            grid.grid[9][y] = 1;
+        }
+        graph.rebuild(&grid, &HashSet::from([UVec2::new(0, 0)]));
+        assert_eq!(
+            graph.find_route(&grid, UVec2::new(2, 2), UVec2::new(15, 2)),
+            None
+        );
+        grid.grid[9][4] = 0;
+        graph.rebuild(&grid, &HashSet::from([UVec2::new(0, 0)]));
+        let route: Vec<UVec2> = graph
+            .find_route(&grid, UVec2::new(2, 2), UVec2::new(15, 2))
+            .unwrap();
+        assert_eq!(route[0], UVec2::new(10, 4));
+    }
+}
@@ -0,0 +1,176 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_resource::{
+            BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Extent3d, ImageCopyBuffer,
+            ImageDataLayout, Maintain, MapMode, TextureDimension, TextureFormat,
+        },
+        renderer::{render_system, RenderDevice, RenderQueue},
+        texture::GpuImage,
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet,
+    },
+};
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Mutex,
+};
+
+/// Asks for a copy of an image which only lives on the GPU, e.g. a camera's render target.
+/// The copy arrives as [`ImageCaptured`] a frame later.
+#[derive(Event)]
+pub struct CaptureImage(pub Handle<Image>);
+#[derive(Event)]
+pub struct ImageCaptured {
+    pub source: Handle<Image>,
+    pub image: Image,
+}
+
+/// Images requested this frame, handed to the render world during extraction
+#[derive(Resource, Default, Clone)]
+struct PendingCaptures(Vec<Handle<Image>>);
+struct CapturedData {
+    source: Handle<Image>,
+    size: UVec2,
+    format: TextureFormat,
+    data: Vec<u8>,
+}
+#[derive(Resource)]
+struct CaptureSender(Sender<CapturedData>);
+#[derive(Resource)]
+struct CaptureReceiver(Mutex<Receiver<CapturedData>>);
+
+pub struct ImageCapturePlugin;
+impl Plugin for ImageCapturePlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = channel();
+        app.add_event::<CaptureImage>()
+            .add_event::<ImageCaptured>()
+            .init_resource::<PendingCaptures>()
+            .insert_resource(CaptureReceiver(Mutex::new(receiver)))
+            .add_systems(Update, (queue_captures, receive_captures));
+        let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+        render_app
+            .insert_resource(CaptureSender(sender))
+            .init_resource::<PendingCaptures>()
+            .add_systems(ExtractSchedule, extract_captures)
+            .add_systems(
+                Render,
+                read_back_images
+                    .after(render_system)
+                    .in_set(RenderSet::Render),
+            );
+    }
+}
+/// Only the requests of the current frame are kept, so every request is extracted exactly once
+fn queue_captures(
+    mut capture_events: EventReader<CaptureImage>,
+    mut pending_captures: ResMut<PendingCaptures>,
+) {
+    pending_captures.0 = capture_events
+        .read()
+        .map(|capture| capture.0.clone())
+        .collect();
+}
+fn extract_captures(mut commands: Commands, pending_captures: Extract<Res<PendingCaptures>>) {
+    commands.insert_resource(pending_captures.clone());
+}
+/// Copies the requested textures into mappable buffers once the frame has been rendered
+fn read_back_images(
+    pending_captures: Res<PendingCaptures>,
+    gpu_images: Res<RenderAssets<GpuImage>>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    capture_sender: Res<CaptureSender>,
+) {
+    for source in &pending_captures.0 {
+        let Some(gpu_image) = gpu_images.get(source) else {
+            warn!("Image to capture is not on the GPU");
+            continue;
+        };
+        let Some(block_size) = gpu_image.texture_format.block_copy_size(None) else {
+            warn!(
+                "Can not capture images of format {:?}",
+                gpu_image.texture_format
+            );
+            continue;
+        };
+        let bytes_per_row: usize = gpu_image.size.x as usize * block_size as usize;
+        // Rows of the buffer have to be aligned, the padding is stripped again below
+        let padded_bytes_per_row: usize = RenderDevice::align_copy_bytes_per_row(bytes_per_row);
+        let buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("image_capture_buffer"),
+            size: (padded_bytes_per_row * gpu_image.size.y as usize) as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder =
+            render_device.create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_texture_to_buffer(
+            gpu_image.texture.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &buffer,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row as u32),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: gpu_image.size.x,
+                height: gpu_image.size.y,
+                depth_or_array_layers: 1,
+            },
+        );
+        render_queue.submit([encoder.finish()]);
+
+        let buffer_slice = buffer.slice(..);
+        let (mapped_sender, mapped_receiver) = channel();
+        buffer_slice.map_async(MapMode::Read, move |result| {
+            let _ = mapped_sender.send(result);
+        });
+        render_device.poll(Maintain::Wait).panic_on_timeout();
+        if !matches!(mapped_receiver.recv(), Ok(Ok(()))) {
+            error!("Could not read the captured image back from the GPU");
+            continue;
+        }
+        let data: Vec<u8> = buffer_slice
+            .get_mapped_range()
+            .chunks(padded_bytes_per_row)
+            .flat_map(|row| row[..bytes_per_row].iter().copied())
+            .collect();
+        buffer.unmap();
+        let _ = capture_sender.0.send(CapturedData {
+            source: source.clone(),
+            size: gpu_image.size,
+            format: gpu_image.texture_format,
+            data,
+        });
+    }
+}
+fn receive_captures(
+    capture_receiver: Res<CaptureReceiver>,
+    mut captured_events: EventWriter<ImageCaptured>,
+) {
+    let Ok(receiver) = capture_receiver.0.lock() else {
+        return;
+    };
+    for captured in receiver.try_iter() {
+        captured_events.send(ImageCaptured {
+            source: captured.source,
+            image: Image::new(
+                Extent3d {
+                    width: captured.size.x,
+                    height: captured.size.y,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                captured.data,
+                captured.format,
+                RenderAssetUsages::MAIN_WORLD,
+            ),
+        });
+    }
+}
@@ -1,21 +1,40 @@
 // #![feature(let_chains)]
 mod a_star;
+mod avoidance;
 mod civilisation;
 mod environment;
+mod flow_field;
+mod formation;
+mod hpa_star;
+mod mesh_collider;
 mod movable;
+mod move_requests;
+mod obstacles;
+mod orders;
 mod ownable;
+mod path_debug;
+mod pathfinding;
+mod placement;
 mod player_controller;
+mod production;
 mod resource_collection;
 mod resources;
 mod spawner;
+mod stats;
 mod ui;
+mod unit_loader;
 mod utils;
 
 use crate::environment::Environment;
 use crate::movable::UnitMovement;
+use crate::move_requests::MoveRequests;
+use crate::obstacles::ObstacleStamping;
+use crate::orders::Orders;
+use crate::path_debug::PathDebug;
 use crate::player_controller::PlayerController;
+use crate::production::Production;
 use crate::spawner::InstanceSpawner;
-// use crate::ui::GameUI;
+use crate::ui::GameUI;
 use bevy::{
     prelude::*,
     utils::HashMap,
@@ -23,7 +42,7 @@ use bevy::{
 };
 use bevy_rapier3d::prelude::*;
 use civilisation::CivilisationPlugin;
-use player_controller::{Civilisation, ContextMenuAction, LocalPlayer, PlayerInfo, TechLevel};
+use player_controller::{Civilisation, LocalPlayer, PlayerInfo, TechLevel};
 use resource_collection::ResourceCollection;
 use resources::{ResourceStockpiles, ResourceType};
 use spawner::{InstanceSpawnRequest, UnitType};
@@ -49,9 +68,14 @@ fn main() {
             PlayerController,
             Environment,
             UnitMovement,
+            MoveRequests,
+            ObstacleStamping,
+            Orders,
+            PathDebug,
             InstanceSpawner,
             GameUI,
             ResourceCollection,
+            Production,
             RapierPhysicsPlugin::<NoUserData>::default(),
             CivilisationPlugin,
             RapierDebugRenderPlugin::default(),
@@ -62,19 +86,12 @@ fn main() {
 }
 
 fn setup(mut commands: Commands, mut spawn_events: EventWriter<InstanceSpawnRequest>) {
-    let mut player_info: PlayerInfo = PlayerInfo {
+    // Build actions are filled in from the `produces` list of the unit files
+    let player_info: PlayerInfo = PlayerInfo {
         civilisation: Civilisation::Greek,
         tech_level: TechLevel::L0,
         context_menu_actions: HashMap::new(),
     };
-
-    player_info.context_menu_actions.insert(
-        UnitType::Spacestation,
-        vec![
-            ContextMenuAction::Build(UnitType::Cruiser),
-            ContextMenuAction::Build(UnitType::MiningStation),
-        ],
-    );
     commands.spawn((
         LocalPlayer,
         player_info,
@@ -0,0 +1,180 @@
+use crate::spawner::UnitSpecification;
+use bevy::{asset::LoadState, gltf::Gltf, prelude::*, utils::HashMap};
+use bevy_rapier3d::{prelude::*, rapier::prelude::ShapeType};
+
+/// Shapes which are not described by `dimensions` but derived from the unit's model
+pub fn is_mesh_shape(shape: ShapeType) -> bool {
+    matches!(
+        shape,
+        ShapeType::TriMesh | ShapeType::ConvexPolyhedron | ShapeType::RoundConvexPolyhedron
+    )
+}
+
+/// Marks a unit whose collider will be computed from its glTF as soon as the file is loaded
+#[derive(Component)]
+pub struct PendingMeshCollider {
+    pub gltf: Handle<Gltf>,
+    /// Scene of the glTF the unit shows, only its meshes make up the collider
+    pub scene: Handle<Scene>,
+    pub shape: ShapeType,
+    pub border_radius: f32,
+    /// Used if the model does not yield a usable collider
+    pub fallback_radius: f32,
+}
+impl PendingMeshCollider {
+    pub fn new(unit_specification: &UnitSpecification, asset_server: &AssetServer) -> Self {
+        let path: String = unit_specification.file_path.replace("./assets/", "");
+        PendingMeshCollider {
+            scene: asset_server.load(path.clone() + "#" + &unit_specification.scene),
+            gltf: asset_server.load(path),
+            shape: unit_specification.shape.0,
+            border_radius: unit_specification.border_radius,
+            fallback_radius: unit_specification.dimensions.max_element(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct MeshColliderKey {
+    gltf: AssetId<Gltf>,
+    scene: AssetId<Scene>,
+    shape: ShapeType,
+    border_radius: u32,
+}
+/// Colliders are computed once per scene and shape, every further unit gets a clone
+#[derive(Resource, Default)]
+struct MeshColliderCache(HashMap<MeshColliderKey, Option<Collider>>);
+
+pub struct MeshColliders;
+impl Plugin for MeshColliders {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MeshColliderCache>().add_systems(
+            Update,
+            (
+                invalidate_mesh_colliders,
+                attach_mesh_colliders.after(invalidate_mesh_colliders),
+            ),
+        );
+    }
+}
+fn invalidate_mesh_colliders(
+    mut gltf_events: EventReader<AssetEvent<Gltf>>,
+    mut mesh_collider_cache: ResMut<MeshColliderCache>,
+) {
+    for gltf_event in gltf_events.read() {
+        if let AssetEvent::Modified { id } = gltf_event {
+            mesh_collider_cache.0.retain(|key, _| key.gltf != *id);
+        }
+    }
+}
+fn attach_mesh_colliders(
+    mut commands: Commands,
+    pending_units: Query<(Entity, &PendingMeshCollider)>,
+    mut mesh_collider_cache: ResMut<MeshColliderCache>,
+    asset_server: Res<AssetServer>,
+    scenes: Res<Assets<Scene>>,
+    meshes: Res<Assets<Mesh>>,
+) {
+    for (entity, pending) in pending_units.iter() {
+        let key = MeshColliderKey {
+            gltf: pending.gltf.id(),
+            scene: pending.scene.id(),
+            shape: pending.shape,
+            border_radius: pending.border_radius.to_bits(),
+        };
+        if !mesh_collider_cache.0.contains_key(&key) {
+            if let Some(scene) = scenes.get(&pending.scene) {
+                let Some((vertices, indices)) = scene_geometry(scene, &meshes) else {
+                    continue;
+                };
+                let collider: Option<Collider> = match pending.shape {
+                    ShapeType::TriMesh if !indices.is_empty() => {
+                        Some(Collider::trimesh(vertices, indices))
+                    }
+                    ShapeType::ConvexPolyhedron => Collider::convex_hull(&vertices),
+                    ShapeType::RoundConvexPolyhedron => {
+                        Collider::round_convex_hull(&vertices, pending.border_radius)
+                    }
+                    _ => None,
+                };
+                mesh_collider_cache.0.insert(key, collider);
+            } else if matches!(asset_server.load_state(&pending.gltf), LoadState::Failed(_))
+                || matches!(
+                    asset_server.load_state(&pending.scene),
+                    LoadState::Failed(_)
+                )
+            {
+                // Units would wait for a model which never arrives otherwise
+                warn!(
+                    "Could not load the model {:?}",
+                    asset_server.get_path(&pending.scene)
+                );
+                mesh_collider_cache.0.insert(key, None);
+            } else {
+                continue;
+            }
+        }
+        let collider: Collider = match &mesh_collider_cache.0[&key] {
+            Some(collider) => collider.clone(),
+            None => {
+                warn!(
+                    "Could not derive a {:?} collider from the model, using a ball",
+                    pending.shape
+                );
+                Collider::ball(pending.fallback_radius)
+            }
+        };
+        commands
+            .entity(entity)
+            .insert(collider)
+            .remove::<PendingMeshCollider>();
+    }
+}
+/// Collects the triangles of every mesh in the scene in model space, i.e. with the transforms of
+/// their parents applied. Scaling by `prescaling` happens through the unit's transform.
+fn scene_geometry(scene: &Scene, meshes: &Assets<Mesh>) -> Option<(Vec<Vec3>, Vec<[u32; 3]>)> {
+    let mut vertices: Vec<Vec3> = Vec::new();
+    let mut indices: Vec<[u32; 3]> = Vec::new();
+    for entity in scene.world.iter_entities() {
+        let Some(mesh) = entity.get::<Handle<Mesh>>() else {
+            continue;
+        };
+        let mesh: &Mesh = meshes.get(mesh)?;
+        let transform: Mat4 = model_transform(&scene.world, entity.id())?;
+        let Some(positions) = mesh
+            .attribute(Mesh::ATTRIBUTE_POSITION)
+            .and_then(|positions| positions.as_float3())
+        else {
+            continue;
+        };
+        let offset: u32 = vertices.len() as u32;
+        vertices.extend(
+            positions
+                .iter()
+                .map(|position| transform.transform_point3(Vec3::from(*position))),
+        );
+        let mesh_indices: Vec<u32> = match mesh.indices() {
+            Some(mesh_indices) => mesh_indices.iter().map(|i| i as u32).collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        indices.extend(
+            mesh_indices
+                .chunks_exact(3)
+                .map(|triangle| [triangle[0], triangle[1], triangle[2]].map(|i| i + offset)),
+        );
+    }
+    Some((vertices, indices))
+}
+/// Transform of a scene entity relative to the scene root
+fn model_transform(world: &World, entity: Entity) -> Option<Mat4> {
+    let mut transform: Mat4 = Mat4::IDENTITY;
+    let mut current: Option<Entity> = Some(entity);
+    while let Some(entity) = current {
+        let entity_ref = world.get_entity(entity)?;
+        if let Some(local) = entity_ref.get::<Transform>() {
+            transform = local.compute_matrix() * transform;
+        }
+        current = entity_ref.get::<Parent>().map(|parent| parent.get());
+    }
+    Some(transform)
+}
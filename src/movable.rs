@@ -1,217 +1,241 @@
-use crate::a_star::{a_star, calculate_a_star};
-use crate::environment::MovementGrid;
+use crate::a_star::{a_star, calculate_a_star, AStarParams, PathFailed, PathfindingSettings};
+use crate::avoidance::{avoid_collisions, AvoidanceSettings};
+use crate::environment::{setup_movement_grid, MovementGrid};
+use crate::flow_field::{
+    finish_flow_fields, remove_unused_flow_fields, start_group_moves, FlowField, FlowFieldFollower,
+    FlowFieldTask, GroupMoveCommand,
+};
+use crate::formation::{select_formation, SelectedFormation, SpeedLimit};
+use crate::hpa_star::{build_cluster_graph, refine_route, update_cluster_graph, HierarchicalRoute};
+use crate::move_requests::RequestedMove;
+use crate::pathfinding::{heading_towards, Heading, PathNode};
+use crate::stats::{StatId, Stats};
 use bevy::ecs::component::Component;
+use bevy::ecs::system::EntityCommands;
 use bevy::math::Vec3;
 use bevy::prelude::*;
 use bevy::transform::components::Transform;
-use std::f32::consts::PI;
+use std::f32::consts::{FRAC_PI_2, PI};
 use std::time::Duration;
-use strum::IntoEnumIterator;
-use strum_macros::EnumIter;
+
+/// Used for units without speed or turn rate stats
+const DEFAULT_SPEED: f32 = 1.0;
+const DEFAULT_TURN_RATE: f32 = 1.0;
+/// Lower bound of the turn rate, so a zero stat does not make the turning radius endless
+const MIN_TURN_RATE: f32 = 0.01;
+/// Distance at which intermediate path nodes count as reached
+const WAYPOINT_RADIUS: f32 = 0.1;
 pub struct UnitMovement;
 
 impl Plugin for UnitMovement {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, (a_star, calculate_a_star))
-            .add_systems(Update, move_units)
+        app.init_resource::<PathfindingSettings>()
+            .init_resource::<AvoidanceSettings>()
+            .add_event::<GroupMoveCommand>()
+            .add_event::<PathFailed>()
+            .init_resource::<SelectedFormation>()
+            .add_systems(Update, select_formation)
+            .add_systems(Startup, build_cluster_graph.after(setup_movement_grid))
+            .add_systems(
+                Update,
+                (
+                    update_cluster_graph,
+                    a_star.after(update_cluster_graph),
+                    calculate_a_star.after(a_star),
+                    refine_route.after(calculate_a_star),
+                ),
+            )
+            .add_systems(
+                Update,
+                (
+                    start_group_moves,
+                    finish_flow_fields,
+                    move_units,
+                    avoid_collisions,
+                    remove_unused_flow_fields,
+                )
+                    .chain(),
+            )
             .insert_resource(MovementTimer(Timer::new(
                 Duration::from_millis(1500),
                 TimerMode::Repeating,
             )));
     }
 }
-pub const DISTANCE_FACTOR: f32 = 1.0;
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
-pub struct NodeCoords {
-    pub xy: UVec2,
-    pub h: Option<Heading>,
-}
-#[derive(Debug, Clone, Copy)]
-pub struct PathNode {
-    pub xy: Vec2,
-    pub h: Heading,
-}
-#[derive(Eq, PartialEq, Hash, Clone, Copy, EnumIter, Debug, Default)]
-pub enum Heading {
-    #[default]
-    N,
-    // NNE,
-    NE,
-    // NEE,
-    E,
-    // SEE,
-    SE,
-    // SSE,
-    S,
-    // SSW,
-    SW,
-    // SWW,
-    W,
-    // NWW,
-    NW,
-}
 #[derive(Component)]
 pub struct MoveCommand {
     pub target: Vec2,
 }
-#[derive(Component)]
-pub struct Movable {}
+#[derive(Component, Default)]
+pub struct Movable {
+    /// Current speed, kept between path nodes so units do not stop at every one of them
+    pub speed: f32,
+}
+
+/// Units which are on their way or still looking for it
+pub type Moving = Or<(
+    With<MoveCommand>,
+    With<MovementPath>,
+    With<AStarParams>,
+    With<HierarchicalRoute>,
+    With<FlowFieldFollower>,
+)>;
 
 #[derive(Resource)]
 struct MovementTimer(Timer);
 #[derive(Component)]
+/// Remaining nodes of the path, the next node to head for is the last one
 pub struct MovementPath {
     pub path: Vec<PathNode>,
 }
 
-fn calculate_course_deflection(start: &NodeCoords, end: &NodeCoords) -> u32 {
-    let difference: i32 = (start.h.unwrap() as i32 - end.h.unwrap() as i32).abs();
-    let half_headings: i32 = (Heading::iter().len() as f32 / 2.0).ceil() as i32;
-    (half_headings - (difference - half_headings).abs()) as u32
-}
-pub fn inertia_based_inter_cell_movement(from: NodeCoords, to: NodeCoords) -> f32 {
-    let inertia: f32 = 1.0;
-    let course_deflection: f32 = calculate_course_deflection(&from, &to) as f32;
-    let cost: f32 =
-        from.xy.as_vec2().distance(to.xy.as_vec2()).abs() + (course_deflection * inertia);
-    cost
+/// Drops everything the unit was doing to reach its current target
+pub fn halt(entity_commands: &mut EntityCommands) {
+    entity_commands.remove::<(
+        MoveCommand,
+        MovementPath,
+        AStarParams,
+        HierarchicalRoute,
+        FlowFieldFollower,
+        SpeedLimit,
+        RequestedMove,
+    )>();
 }
-pub fn heuristical_distance(from: NodeCoords, to: NodeCoords) -> f32 {
-    from.xy.as_vec2().distance(to.xy.as_vec2())
+/// How fast a unit may move, accelerate, brake and turn, read from its stats
+#[derive(Clone, Copy, Debug)]
+pub struct MovementLimits {
+    pub max_speed: f32,
+    pub acceleration: f32,
+    pub deceleration: f32,
+    /// Radians per second
+    pub turn_rate: f32,
 }
-pub fn calculate_heading(from: &UVec2, to: &UVec2) -> Heading {
-    let diff: IVec2 = to.as_ivec2() - from.as_ivec2();
-    let heading: Heading;
-    if diff.x == -1 && diff.y == 0 {
-        heading = Heading::E
-    } else if diff.x == -1 && diff.y == 1 {
-        heading = Heading::NE
-    } else if diff.x == 0 && diff.y == 1 {
-        heading = Heading::N
-    } else if diff.x == 1 && diff.y == 1 {
-        heading = Heading::NW
-    } else if diff.x == 1 && diff.y == 0 {
-        heading = Heading::W
-    } else if diff.x == 1 && diff.y == -1 {
-        heading = Heading::SW
-    } else if diff.x == 0 && diff.y == -1 {
-        heading = Heading::S
-    } else {
-        heading = Heading::SE
+impl MovementLimits {
+    /// Units without acceleration or deceleration stats reach full speed or stop within a second
+    pub fn new(stats: Option<&Stats>, speed_limit: Option<&SpeedLimit>) -> Self {
+        let stat =
+            |stat: StatId, default: f32| stats.and_then(|stats| stats.get(stat)).unwrap_or(default);
+        let speed: f32 = stat(StatId::Speed, DEFAULT_SPEED);
+        MovementLimits {
+            max_speed: speed_limit.map_or(speed, |limit| speed.min(limit.0)),
+            acceleration: stat(StatId::Acceleration, speed),
+            deceleration: stat(StatId::Deceleration, speed),
+            turn_rate: stat(StatId::TurnRate, DEFAULT_TURN_RATE).max(MIN_TURN_RATE),
+        }
     }
-    heading
-}
-pub fn check_path_width(current: UVec2, target: UVec2, gridmap: &MovementGrid) -> bool {
-    if current.x != target.x
-        && current.y != target.y
-        && gridmap.grid[current.x as usize][target.y as usize] != 0
-        && gridmap.grid[target.x as usize][current.y as usize] != 0
-    {
-        return false;
+    /// Radius of the tightest turn at full speed, in cells of the grid
+    pub fn turning_radius(&self, grid: &MovementGrid) -> f32 {
+        self.max_speed / self.turn_rate / grid.settings.cell_size
     }
-
-    true
 }
-pub fn get_neighbours(current: UVec2, gridmap: &MovementGrid) -> Vec<NodeCoords> {
-    let mut neighbours: Vec<NodeCoords> = Vec::new();
-    for x in -1..2 {
-        for y in -1..2 {
-            let adjacent_cell: IVec2 = IVec2 {
-                x: current.x as i32 + x,
-                y: current.y as i32 + y,
-            };
-
-            if adjacent_cell.x >= 0
-                && (adjacent_cell.x as usize) < gridmap.grid.len()
-                && adjacent_cell.y >= 0
-                && (adjacent_cell.y as usize) < gridmap.grid[0].len()
-                && gridmap.grid[adjacent_cell.x as usize][adjacent_cell.y as usize] == 0
-                && adjacent_cell.as_uvec2() != current
-                && check_path_width(current, adjacent_cell.as_uvec2(), gridmap)
-            {
-                neighbours.push(NodeCoords {
-                    xy: UVec2 {
-                        x: adjacent_cell.x as u32,
-                        y: adjacent_cell.y as u32,
-                    },
-                    h: Some(calculate_heading(&current, &adjacent_cell.as_uvec2())),
-                });
-            }
-        }
-    }
-    neighbours
+/// True if the path leads to the end of the unit's move. Routes keep their component with no
+/// waypoints left while their last leg is followed.
+pub fn is_final_leg(movement_path: &MovementPath, route: Option<&HierarchicalRoute>) -> bool {
+    movement_path.path.len() <= 1 && route.map_or(true, |route| route.waypoints.is_empty())
+}
+/// Heading the unit currently faces, models face +z
+pub fn current_heading(transform: &Transform) -> Heading {
+    let facing: Vec3 = transform.rotation * Vec3::Z;
+    heading_towards(Vec2::ZERO, facing.xz())
 }
 
+/// Moves the unit along its facing while turning it towards the target, so it follows an arc.
+/// The speed changes by at most the acceleration or deceleration. Units slow down where their
+/// turning circle would miss the target and, if `stop` is set, to come to rest on it.
+/// Returns true once the target is reached.
 fn move_towards(
     transform: &mut Transform,
-    speed: f64,
-    rotation_speed: f64,
-    delta: f64,
-    target: &PathNode,
+    speed: &mut f32,
+    limits: &MovementLimits,
+    delta: f32,
+    target: Vec2,
+    stop: bool,
 ) -> bool {
-    let mut target_reached: bool = false;
-    let target_scaled: Vec3 = Vec3 {
-        x: target.xy.x,
-        y: transform.translation.y,
-        z: target.xy.y,
-    }; // TODO make this dynamic or calculate in the reconstruct_path
-
-    let translation_direction: Vec3 = target_scaled - transform.translation;
-    let euler_rotation: (f32, f32, f32) = transform.rotation.to_euler(EulerRot::YXZ);
-    let mut directional_euler_fraction: f32 =
-        (Heading::iter().len() as u32 - target.h as u32) as f32 / (Heading::iter().len() as f32);
-    directional_euler_fraction *= 2.0 * PI;
-    directional_euler_fraction = (directional_euler_fraction + 2.0 * PI) % (2.0 * PI);
-    if directional_euler_fraction > PI {
-        directional_euler_fraction -= 2.0 * PI;
+    let offset: Vec2 = target - transform.translation.xz();
+    let distance: f32 = offset.length();
+    if distance <= f32::EPSILON {
+        if stop {
+            *speed = 0.0;
+        }
+        return true;
     }
+    // Models face +z, a yaw of 0 points north
+    let facing: Vec3 = transform.rotation * Vec3::Z;
+    let course_change: f32 =
+        (offset.x.atan2(offset.y) - facing.x.atan2(facing.z) + PI).rem_euclid(2.0 * PI) - PI;
+    let max_turn: f32 = limits.turn_rate * delta;
+    transform.rotate_y(course_change.clamp(-max_turn, max_turn));
 
-    let target_rotation: Vec3 = Vec3 {
-        x: 0.0,
-        y: directional_euler_fraction,
-        z: 0.0,
+    // Radius of the arc tangent to the current facing which runs through the target
+    let arc_radius: f32 = if course_change.abs() < FRAC_PI_2 {
+        distance / (2.0 * course_change.abs().sin())
+    } else {
+        distance / 2.0
     };
-    let rotation_direction: Vec3 = (target_rotation
-        - Vec3 {
-            x: euler_rotation.1,
-            y: euler_rotation.0,
-            z: euler_rotation.2,
-        })
-    .normalize_or_zero()
-        * rotation_speed as f32
-        * 1.
-        * delta as f32;
-    if rotation_direction != Vec3::ZERO {
-        transform.rotate(Quat::from_euler(
-            EulerRot::YXZ,
-            rotation_direction.y,
-            rotation_direction.x,
-            rotation_direction.z,
-        ));
+    let mut target_speed: f32 = limits.max_speed.min(limits.turn_rate * arc_radius);
+    if stop {
+        target_speed = target_speed.min((2.0 * limits.deceleration * distance).sqrt());
     }
-    let translation_vector: Vec3 = translation_direction.normalize() * (speed * delta) as f32;
-
-    if translation_vector.length() >= translation_direction.length()
-        || translation_direction == Vec3::ZERO
-    {
-        transform.translation = target_scaled;
-        target_reached = true;
+    *speed = if target_speed > *speed {
+        (*speed + limits.acceleration * delta).min(target_speed)
     } else {
-        transform.translation += translation_vector;
+        (*speed - limits.deceleration * delta).max(target_speed)
+    };
+
+    let step: f32 = *speed * delta;
+    if stop && step >= distance {
+        transform.translation.x = target.x;
+        transform.translation.z = target.y;
+        *speed = 0.0;
+        return true;
     }
-    target_reached
+    let facing: Vec3 = transform.rotation * Vec3::Z;
+    transform.translation += Vec3::new(facing.x, 0.0, facing.z).normalize_or_zero() * step;
+    !stop && distance <= step.max(WAYPOINT_RADIUS)
 }
 fn move_units(
-    mut movables: Query<(Entity, &mut Transform, &mut MovementPath)>,
+    mut movables: Query<
+        (
+            Entity,
+            &mut Transform,
+            &mut Movable,
+            &mut MovementPath,
+            Option<&HierarchicalRoute>,
+            Option<&Stats>,
+            Option<&SpeedLimit>,
+        ),
+        Without<FlowFieldFollower>,
+    >,
+    mut followers: Query<
+        (
+            Entity,
+            &mut Transform,
+            &mut Movable,
+            &mut FlowFieldFollower,
+            Option<&Stats>,
+            Option<&SpeedLimit>,
+        ),
+        Without<MovementPath>,
+    >,
+    flow_fields: Query<&FlowField>,
+    pending_flow_fields: Query<(), With<FlowFieldTask>>,
+    gridmap: Res<MovementGrid>,
     time: Res<Time>,
     mut commands: Commands,
 ) {
-    let speed: f64 = 1.0;
-    let rotation_speed: f64 = 1.0;
-    for (entity, mut transform, mut movementpath) in movables.iter_mut() {
+    let delta: f32 = time.delta_seconds();
+    for (entity, mut transform, mut movable, mut movementpath, route, stats, speed_limit) in
+        movables.iter_mut()
+    {
+        let limits: MovementLimits = MovementLimits::new(stats, speed_limit);
+        // Only the end of the whole route is approached to a stop
+        let stop: bool = is_final_leg(&movementpath, route);
         let node: &PathNode = match movementpath.path.last() {
             Some(n) => n,
             None => {
+                if stop {
+                    movable.speed = 0.0;
+                }
                 commands.entity(entity).remove::<MovementPath>();
                 continue;
             }
@@ -219,13 +243,177 @@ fn move_units(
 
         if move_towards(
             &mut transform,
-            speed,
-            rotation_speed,
-            time.delta().as_secs_f64(),
-            node,
+            &mut movable.speed,
+            &limits,
+            delta,
+            node.xy,
+            stop,
         ) {
             commands.entity(entity).remove::<MoveCommand>();
             movementpath.path.pop();
         }
     }
+    for (entity, mut transform, mut movable, mut follower, stats, speed_limit) in
+        followers.iter_mut()
+    {
+        let limits: MovementLimits = MovementLimits::new(stats, speed_limit);
+        // Units wait for fields which are still being computed
+        if pending_flow_fields.contains(follower.field) {
+            movable.speed = 0.0;
+            continue;
+        }
+        let Ok(flow_field) = flow_fields.get(follower.field) else {
+            commands.entity(entity).remove::<FlowFieldFollower>();
+            continue;
+        };
+        let cell: UVec2 = match follower.next {
+            Some((cell, node)) => {
+                if !move_towards(
+                    &mut transform,
+                    &mut movable.speed,
+                    &limits,
+                    delta,
+                    node.xy,
+                    false,
+                ) {
+                    continue;
+                }
+                cell
+            }
+            None => match gridmap.cell_at(transform.translation.xz()) {
+                Some(cell) => cell,
+                None => {
+                    commands.entity(entity).remove::<FlowFieldFollower>();
+                    continue;
+                }
+            },
+        };
+        if cell == flow_field.goal
+            || gridmap.cell_center(cell).distance(flow_field.target) <= flow_field.arrival_radius
+        {
+            follower.next = None;
+        } else {
+            follower.next = flow_field.step(&gridmap, cell);
+        }
+        if follower.next.is_none() {
+            // Close to the target, or cut off from it in the field, units plan their own path
+            commands
+                .entity(entity)
+                .remove::<FlowFieldFollower>()
+                .insert(MoveCommand {
+                    target: follower.slot,
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: MovementLimits = MovementLimits {
+        max_speed: 2.0,
+        acceleration: 1.0,
+        deceleration: 2.0,
+        turn_rate: 1.0,
+    };
+    const DELTA: f32 = 0.05;
+
+    #[test]
+    fn units_accelerate_from_rest() {
+        let mut transform: Transform = Transform::IDENTITY;
+        let mut speed: f32 = 0.0;
+        move_towards(
+            &mut transform,
+            &mut speed,
+            &LIMITS,
+            DELTA,
+            Vec2::new(0.0, 20.0),
+            true,
+        );
+        assert!((speed - LIMITS.acceleration * DELTA).abs() < 1e-6);
+        for _ in 0..100 {
+            move_towards(
+                &mut transform,
+                &mut speed,
+                &LIMITS,
+                DELTA,
+                Vec2::new(0.0, 20.0),
+                true,
+            );
+        }
+        assert_eq!(speed, LIMITS.max_speed);
+    }
+
+    #[test]
+    fn units_brake_to_a_stop_on_the_final_node() {
+        let mut transform: Transform = Transform::IDENTITY;
+        let mut speed: f32 = LIMITS.max_speed;
+        let target: Vec2 = Vec2::new(0.0, 3.0);
+        let mut previous_speed: f32 = speed;
+        let mut steps: u32 = 0;
+        while !move_towards(&mut transform, &mut speed, &LIMITS, DELTA, target, true) {
+            if transform.translation.z > 2.0 {
+                assert!(speed <= previous_speed);
+            }
+            previous_speed = speed;
+            steps += 1;
+            assert!(steps < 1000);
+        }
+        assert_eq!(transform.translation.xz(), target);
+        assert_eq!(speed, 0.0);
+    }
+
+    #[test]
+    fn units_brake_on_the_last_leg_of_a_route() {
+        let target: Vec2 = Vec2::new(0.0, 3.0);
+        let mut movement_path: MovementPath = MovementPath {
+            path: vec![PathNode {
+                xy: target,
+                h: Heading::N,
+            }],
+        };
+        let mut route: HierarchicalRoute = HierarchicalRoute {
+            waypoints: vec![UVec2::new(0, 20)],
+        };
+        assert!(!is_final_leg(&movement_path, Some(&route)));
+        route.waypoints.clear();
+        assert!(is_final_leg(&movement_path, Some(&route)));
+        assert!(is_final_leg(&movement_path, None));
+        let mut transform: Transform = Transform::IDENTITY;
+        let mut speed: f32 = LIMITS.max_speed;
+        let mut steps: u32 = 0;
+        while !move_towards(
+            &mut transform,
+            &mut speed,
+            &LIMITS,
+            DELTA,
+            target,
+            is_final_leg(&movement_path, Some(&route)),
+        ) {
+            steps += 1;
+            assert!(steps < 1000);
+        }
+        movement_path.path.pop();
+        assert_eq!(speed, 0.0);
+        assert!(is_final_leg(&movement_path, Some(&route)));
+    }
+
+    #[test]
+    fn units_turn_along_an_arc() {
+        let mut transform: Transform = Transform::IDENTITY;
+        let mut speed: f32 = 1.0;
+        let target: Vec2 = Vec2::new(-4.0, 0.0);
+        move_towards(&mut transform, &mut speed, &LIMITS, DELTA, target, false);
+        let facing: Vec3 = transform.rotation * Vec3::Z;
+        // The unit keeps moving while it turns, by no more than its turn rate
+        assert!(transform.translation.z > 0.0);
+        assert!(facing.x < 0.0);
+        assert!(facing.x.atan2(facing.z).abs() <= LIMITS.turn_rate * DELTA + 1e-6);
+        let mut steps: u32 = 0;
+        while !move_towards(&mut transform, &mut speed, &LIMITS, DELTA, target, false) {
+            steps += 1;
+            assert!(steps < 1000);
+        }
+    }
 }
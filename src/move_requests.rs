@@ -0,0 +1,122 @@
+use crate::a_star::{a_star, PathFailed};
+use crate::hpa_star::refine_route;
+use crate::movable::{halt, MoveCommand, Moving};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+/// System which asked for a move, so it can tell its own moves apart in the reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoveRequester {
+    Collection,
+}
+/// Asks for a unit to be moved to the target, replacing whatever it was doing
+#[derive(Event, Clone, Copy, Debug)]
+pub struct MoveRequest {
+    pub entity: Entity,
+    pub target: Vec2,
+    pub requester: MoveRequester,
+}
+/// Sent when a unit got as far as it could on a requested move
+#[derive(Event, Clone, Copy, Debug)]
+pub struct MoveArrived {
+    pub entity: Entity,
+    pub position: Vec2,
+    pub requester: MoveRequester,
+}
+/// Sent when a requested move ended early, because no path was found or the unit got other
+/// orders
+#[derive(Event, Clone, Copy, Debug)]
+pub struct MoveCancelled {
+    pub entity: Entity,
+    pub position: Vec2,
+    pub requester: MoveRequester,
+}
+/// Unit on a requested move, halting it removes the marker and cancels the move
+#[derive(Component)]
+pub struct RequestedMove;
+/// Requester of every move which has not been reported yet
+#[derive(Resource, Default)]
+struct RequestedMoves(HashMap<Entity, MoveRequester>);
+
+pub struct MoveRequests;
+impl Plugin for MoveRequests {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RequestedMoves>()
+            .add_event::<MoveRequest>()
+            .add_event::<MoveArrived>()
+            .add_event::<MoveCancelled>()
+            .add_systems(
+                Update,
+                (
+                    start_requested_moves.before(a_star),
+                    report_requested_moves.after(refine_route),
+                ),
+            );
+    }
+}
+pub fn start_requested_moves(
+    mut move_requests: EventReader<MoveRequest>,
+    units: Query<&Transform>,
+    mut requested_moves: ResMut<RequestedMoves>,
+    mut cancellations: EventWriter<MoveCancelled>,
+    mut commands: Commands,
+) {
+    for request in move_requests.read() {
+        let Ok(transform) = units.get(request.entity) else {
+            continue;
+        };
+        if let Some(requester) = requested_moves.0.insert(request.entity, request.requester) {
+            cancellations.send(MoveCancelled {
+                entity: request.entity,
+                position: transform.translation.xz(),
+                requester,
+            });
+        }
+        let mut entity_commands = commands.entity(request.entity);
+        halt(&mut entity_commands);
+        entity_commands.insert((
+            MoveCommand {
+                target: request.target,
+            },
+            RequestedMove,
+        ));
+    }
+}
+/// Reports requested moves of units which stopped moving as arrived, and those which were
+/// halted or could not find a path as cancelled
+pub fn report_requested_moves(
+    units: Query<(&Transform, Has<RequestedMove>)>,
+    moving: Query<(), Moving>,
+    mut path_failures: EventReader<PathFailed>,
+    mut requested_moves: ResMut<RequestedMoves>,
+    mut arrivals: EventWriter<MoveArrived>,
+    mut cancellations: EventWriter<MoveCancelled>,
+    mut commands: Commands,
+) {
+    let failed: Vec<Entity> = path_failures.read().map(|failure| failure.entity).collect();
+    requested_moves.0.retain(|&entity, &mut requester| {
+        // Despawned units are dropped silently
+        let Ok((transform, requested)) = units.get(entity) else {
+            return false;
+        };
+        let position: Vec2 = transform.translation.xz();
+        if requested && !failed.contains(&entity) {
+            if moving.contains(entity) {
+                return true;
+            }
+            arrivals.send(MoveArrived {
+                entity,
+                position,
+                requester,
+            });
+        } else {
+            cancellations.send(MoveCancelled {
+                entity,
+                position,
+                requester,
+            });
+        }
+        commands.entity(entity).remove::<RequestedMove>();
+        false
+    });
+}
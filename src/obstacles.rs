@@ -0,0 +1,251 @@
+use crate::a_star::{a_star, AStarParams};
+use crate::environment::{MovementGrid, MovementGridChanged};
+use crate::flow_field::FlowFieldFollower;
+use crate::hpa_star::{update_cluster_graph, HierarchicalRoute};
+use crate::movable::{Movable, MoveCommand, MovementPath, Moving};
+use crate::pathfinding::line_of_sight;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_rapier3d::geometry::{Collider, RapierColliderHandle};
+
+/// Blocks the cells of the movement grid covered by the entity's collider
+#[derive(Component)]
+pub struct GridObstacle;
+#[derive(Resource, Default)]
+pub struct ObstacleSettings {
+    /// Units without a move order block the grid as well
+    pub stamp_parked_units: bool,
+}
+/// Cells each obstacle currently blocks
+#[derive(Resource, Default)]
+struct StampedObstacles(HashMap<Entity, Vec<UVec2>>);
+
+pub struct ObstacleStamping;
+impl Plugin for ObstacleStamping {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ObstacleSettings>()
+            .init_resource::<StampedObstacles>()
+            .add_systems(
+                Update,
+                (
+                    stamp_obstacles.before(update_cluster_graph).before(a_star),
+                    replan_blocked_paths.after(stamp_obstacles).before(a_star),
+                ),
+            );
+    }
+}
+/// Radius of the sphere around the entity's origin enclosing the collider
+pub fn collider_radius(collider: &Collider) -> f32 {
+    let bounding_sphere = collider.raw.compute_local_bounding_sphere();
+    bounding_sphere.center().coords.norm() + bounding_sphere.radius()
+}
+/// Cells whose centre lies inside the collider at the given placement
+pub fn collider_cells(
+    grid: &MovementGrid,
+    collider: &Collider,
+    translation: Vec3,
+    rotation: Quat,
+) -> Vec<UVec2> {
+    let radius: f32 = collider_radius(collider);
+    let size: Vec2 = Vec2::new(grid.width() as f32, grid.height() as f32);
+    let lower: Vec2 =
+        (translation.xz() - radius) / grid.settings.cell_size + grid.settings.xy_offset;
+    let upper: Vec2 =
+        (translation.xz() + radius) / grid.settings.cell_size + grid.settings.xy_offset;
+    if upper.cmplt(Vec2::ZERO).any() || lower.cmpge(size).any() {
+        return Vec::new();
+    }
+    let min: UVec2 = lower.floor().max(Vec2::ZERO).as_uvec2();
+    let max: UVec2 = upper.floor().min(size - 1.0).as_uvec2();
+    let mut cells: Vec<UVec2> = Vec::new();
+    for x in min.x..=max.x {
+        for y in min.y..=max.y {
+            let cell: UVec2 = UVec2 { x, y };
+            let center: Vec2 = grid.cell_center(cell);
+            if collider.contains_point(
+                translation,
+                rotation,
+                Vec3::new(center.x, translation.y, center.y),
+            ) {
+                cells.push(cell);
+            }
+        }
+    }
+    cells
+}
+/// Grid cells count the obstacles covering them, so overlapping obstacles can be removed
+/// independently
+fn stamp(grid: &mut MovementGrid, cells: &[UVec2], blocked: bool) {
+    for cell in cells {
+        let value: &mut u8 = &mut grid.grid[cell.x as usize][cell.y as usize];
+        *value = if blocked {
+            value.saturating_add(1)
+        } else {
+            value.saturating_sub(1)
+        };
+    }
+}
+fn changed_area(cells: &[UVec2]) -> Option<MovementGridChanged> {
+    let first: UVec2 = *cells.first()?;
+    Some(cells.iter().fold(
+        MovementGridChanged {
+            min: first,
+            max: first,
+        },
+        |area, &cell| MovementGridChanged {
+            min: area.min.min(cell),
+            max: area.max.max(cell),
+        },
+    ))
+}
+/// Keeps the cells blocked by obstacles in line with their colliders as they spawn, move,
+/// change or disappear
+fn stamp_obstacles(
+    colliders: Query<(
+        Entity,
+        Ref<GlobalTransform>,
+        Ref<Collider>,
+        Has<GridObstacle>,
+        Has<Movable>,
+        Has<RapierColliderHandle>,
+    )>,
+    moving: Query<(), Moving>,
+    obstacle_settings: Res<ObstacleSettings>,
+    mut stamped_obstacles: ResMut<StampedObstacles>,
+    mut gridmap: ResMut<MovementGrid>,
+    mut grid_changes: EventWriter<MovementGridChanged>,
+) {
+    let mut changed_cells: Vec<Vec<UVec2>> = Vec::new();
+    stamped_obstacles.0.retain(|&entity, cells| {
+        if colliders.contains(entity) {
+            return true;
+        }
+        stamp(&mut gridmap, cells, false);
+        changed_cells.push(std::mem::take(cells));
+        false
+    });
+    for (entity, transform, collider, obstacle, movable, registered) in colliders.iter() {
+        // Rapier scales new colliders to their transform when it registers them, until then
+        // their shape has the wrong size
+        if !registered {
+            continue;
+        }
+        let blocks: bool = obstacle
+            || (obstacle_settings.stamp_parked_units && movable && !moving.contains(entity));
+        let previous: Option<&Vec<UVec2>> = stamped_obstacles.0.get(&entity);
+        if !blocks {
+            if let Some(cells) = stamped_obstacles.0.remove(&entity) {
+                stamp(&mut gridmap, &cells, false);
+                changed_cells.push(cells);
+            }
+            continue;
+        }
+        if previous.is_some() && !transform.is_changed() && !collider.is_changed() {
+            continue;
+        }
+        let (_, rotation, translation) = transform.to_scale_rotation_translation();
+        let cells: Vec<UVec2> = collider_cells(&gridmap, &collider, translation, rotation);
+        if previous == Some(&cells) {
+            continue;
+        }
+        if let Some(previous) = stamped_obstacles.0.remove(&entity) {
+            stamp(&mut gridmap, &previous, false);
+            changed_cells.push(previous);
+        }
+        stamp(&mut gridmap, &cells, true);
+        changed_cells.push(cells.clone());
+        stamped_obstacles.0.insert(entity, cells);
+    }
+    grid_changes.send_batch(changed_cells.iter().filter_map(|cells| changed_area(cells)));
+}
+/// Restarts the pathfinding of units whose way got blocked
+fn replan_blocked_paths(
+    mut grid_changes: EventReader<MovementGridChanged>,
+    paths: Query<(
+        Entity,
+        &Transform,
+        &MovementPath,
+        Option<&HierarchicalRoute>,
+    )>,
+    searches: Query<(Entity, &AStarParams, Option<&HierarchicalRoute>)>,
+    followers: Query<(Entity, &FlowFieldFollower)>,
+    gridmap: Res<MovementGrid>,
+    mut commands: Commands,
+) {
+    let changes: Vec<MovementGridChanged> = grid_changes.read().copied().collect();
+    if changes.is_empty() {
+        return;
+    }
+    let newly_blocked = |cell: UVec2| {
+        !gridmap.is_free(cell)
+            && changes
+                .iter()
+                .any(|change| cell.cmpge(change.min).all() && cell.cmple(change.max).all())
+    };
+    let final_target = |route: Option<&HierarchicalRoute>| {
+        route
+            .and_then(|route| route.waypoints.first())
+            .map(|&cell| gridmap.cell_center(cell))
+    };
+    for (entity, transform, movement_path, route) in paths.iter() {
+        // Smoothed paths run straight between their nodes, so the cells in between count too
+        let cells: Vec<Option<UVec2>> = std::iter::once(transform.translation.xz())
+            .chain(movement_path.path.iter().rev().map(|node| node.xy))
+            .map(|position| gridmap.cell_at(position))
+            .collect();
+        let blocked: bool = cells
+            .windows(2)
+            .any(|segment| match (segment[0], segment[1]) {
+                (Some(from), Some(to)) => {
+                    changes.iter().any(|change| {
+                        from.min(to).cmple(change.max).all() && from.max(to).cmpge(change.min).all()
+                    }) && !line_of_sight(&gridmap, from, to, 0)
+                }
+                _ => false,
+            });
+        if !blocked {
+            continue;
+        }
+        // The path is stored back to front, its first node is the target
+        let Some(target) = final_target(route).or(movement_path.path.first().map(|node| node.xy))
+        else {
+            continue;
+        };
+        commands
+            .entity(entity)
+            .remove::<MovementPath>()
+            .insert(MoveCommand { target });
+    }
+    let blocked_cells: Vec<UVec2> = changes
+        .iter()
+        .flat_map(|change| {
+            (change.min.x..=change.max.x)
+                .flat_map(move |x| (change.min.y..=change.max.y).map(move |y| UVec2 { x, y }))
+        })
+        .filter(|&cell| !gridmap.is_free(cell))
+        .collect();
+    for (entity, params, route) in searches.iter() {
+        if !blocked_cells
+            .iter()
+            .any(|&cell| params.search.has_reached(cell))
+        {
+            continue;
+        }
+        let target: Vec2 =
+            final_target(route).unwrap_or(gridmap.cell_center(params.search.target()));
+        commands
+            .entity(entity)
+            .remove::<AStarParams>()
+            .insert(MoveCommand { target });
+    }
+    for (entity, follower) in followers.iter() {
+        if follower.next.is_some_and(|(cell, _)| newly_blocked(cell)) {
+            commands
+                .entity(entity)
+                .remove::<FlowFieldFollower>()
+                .insert(MoveCommand {
+                    target: follower.slot,
+                });
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use crate::a_star::PathFailed;
+use crate::flow_field::{start_group_moves, GroupMoveCommand};
+use crate::formation::SpeedLimit;
+use crate::movable::{halt, Movable, MoveCommand, Moving};
+use crate::move_requests::start_requested_moves;
+use crate::ownable::Selected;
+use crate::spawner::UnitInformation;
+use crate::stats::{StatId, Stats};
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+const MOVE_LINE: Color = Color::srgb(0.2, 0.8, 0.2);
+const ATTACK_MOVE_LINE: Color = Color::srgb(0.9, 0.2, 0.2);
+const PATROL_LINE: Color = Color::srgb(0.2, 0.5, 0.9);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Move(Vec2),
+    /// Moves, but stops for units of other civilisations which come into sensor range on the way
+    AttackMove(Vec2),
+    /// Point of a patrol route, which goes back to the end of the queue once it is reached
+    Patrol(Vec2),
+}
+impl Order {
+    pub fn target(&self) -> Vec2 {
+        match self {
+            Order::Move(target) | Order::AttackMove(target) | Order::Patrol(target) => *target,
+        }
+    }
+    fn line_color(&self) -> Color {
+        match self {
+            Order::Move(_) => MOVE_LINE,
+            Order::AttackMove(_) => ATTACK_MOVE_LINE,
+            Order::Patrol(_) => PATROL_LINE,
+        }
+    }
+}
+/// Orders of a unit, carried out front to back
+#[derive(Component, Default, Debug)]
+pub struct OrderQueue {
+    orders: VecDeque<Order>,
+    /// The first order has been handed to the movement systems
+    started: bool,
+}
+impl OrderQueue {
+    pub fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.orders.iter()
+    }
+    pub fn current(&self) -> Option<Order> {
+        self.orders.front().copied()
+    }
+    /// Replaces all orders. A patrol runs between the target and the given current position.
+    pub fn replace(&mut self, order: Order, position: Vec2) {
+        self.clear();
+        self.orders.push_back(order);
+        if let Order::Patrol(_) = order {
+            self.orders.push_back(Order::Patrol(position));
+        }
+    }
+    pub fn push(&mut self, order: Order) {
+        self.orders.push_back(order);
+    }
+    pub fn clear(&mut self) {
+        self.orders.clear();
+        self.started = false;
+    }
+    /// First order, if it still has to be started
+    fn next_to_start(&self) -> Option<Order> {
+        self.orders.front().copied().filter(|_| !self.started)
+    }
+    /// Drops the started first order. Patrol points go back to the end of the queue, as long as
+    /// there is another one to go to.
+    fn finish_current(&mut self) {
+        if !self.started {
+            return;
+        }
+        self.started = false;
+        if let Some(order) = self.orders.pop_front() {
+            if matches!(order, Order::Patrol(_))
+                && self
+                    .orders
+                    .iter()
+                    .any(|order| matches!(order, Order::Patrol(_)))
+            {
+                self.orders.push_back(order);
+            }
+        }
+    }
+    /// Drops the started first order for good, patrol points included, as it can not be
+    /// carried out
+    fn abandon_current(&mut self) {
+        if !self.started {
+            return;
+        }
+        self.started = false;
+        self.orders.pop_front();
+    }
+}
+/// Attack-moving unit which stopped for a hostile in sensor range
+#[derive(Component)]
+pub struct Engaging(pub Entity);
+#[derive(Resource)]
+pub struct OrderSettings {
+    /// Held while giving an order to append it to the queue
+    pub queue: KeyCode,
+    pub patrol: KeyCode,
+    pub attack_move: KeyCode,
+    /// Clears the queue and holds the unit in place
+    pub stop: KeyCode,
+}
+impl Default for OrderSettings {
+    fn default() -> Self {
+        OrderSettings {
+            queue: KeyCode::ShiftLeft,
+            patrol: KeyCode::KeyP,
+            attack_move: KeyCode::AltLeft,
+            stop: KeyCode::KeyH,
+        }
+    }
+}
+
+pub struct Orders;
+impl Plugin for Orders {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<OrderSettings>().add_systems(
+            Update,
+            (
+                stop_units,
+                engage_hostiles,
+                advance_orders
+                    .after(stop_units)
+                    .after(engage_hostiles)
+                    .before(start_group_moves)
+                    .before(start_requested_moves),
+                draw_order_lines,
+            ),
+        );
+    }
+}
+/// Order for a right-click, depending on the keys held
+pub fn order_for_click(
+    key_input: &ButtonInput<KeyCode>,
+    order_settings: &OrderSettings,
+    target: Vec2,
+) -> Order {
+    if key_input.pressed(order_settings.patrol) {
+        Order::Patrol(target)
+    } else if key_input.pressed(order_settings.attack_move) {
+        Order::AttackMove(target)
+    } else {
+        Order::Move(target)
+    }
+}
+/// Starts the next order of every unit which is done with its current one. Units given the
+/// same order at the same time move as a group. Orders whose target could not be reached are
+/// dropped instead of being tried again.
+fn advance_orders(
+    mut units: Query<(Entity, &mut OrderQueue), Without<Engaging>>,
+    moving: Query<(), Moving>,
+    mut path_failures: EventReader<PathFailed>,
+    mut group_moves: EventWriter<GroupMoveCommand>,
+    mut commands: Commands,
+) {
+    let failed: Vec<Entity> = path_failures.read().map(|failure| failure.entity).collect();
+    let mut started: Vec<(Order, Vec<Entity>)> = Vec::new();
+    for (entity, mut order_queue) in units.iter_mut() {
+        if moving.contains(entity) {
+            continue;
+        }
+        if failed.contains(&entity) {
+            order_queue.abandon_current();
+        } else {
+            order_queue.finish_current();
+        }
+        let Some(order) = order_queue.next_to_start() else {
+            continue;
+        };
+        order_queue.started = true;
+        match started.iter_mut().find(|(other, _)| *other == order) {
+            Some((_, group)) => group.push(entity),
+            None => started.push((order, vec![entity])),
+        }
+    }
+    for (order, units) in started {
+        let target: Vec2 = order.target();
+        if units.len() > 1 {
+            group_moves.send(GroupMoveCommand { units, target });
+        } else {
+            for unit in units {
+                commands
+                    .entity(unit)
+                    .remove::<SpeedLimit>()
+                    .insert(MoveCommand { target });
+            }
+        }
+    }
+}
+fn stop_units(
+    key_input: Res<ButtonInput<KeyCode>>,
+    order_settings: Res<OrderSettings>,
+    mut units: Query<(Entity, &mut OrderQueue, &mut Movable), With<Selected>>,
+    mut commands: Commands,
+) {
+    if !key_input.just_pressed(order_settings.stop) {
+        return;
+    }
+    for (entity, mut order_queue, mut movable) in units.iter_mut() {
+        order_queue.clear();
+        movable.speed = 0.0;
+        let mut entity_commands = commands.entity(entity);
+        halt(&mut entity_commands);
+        entity_commands.remove::<Engaging>();
+    }
+}
+/// Attack-moving units stop when a unit of another civilisation comes into sensor range and
+/// carry on with their order once there is none left
+fn engage_hostiles(
+    mut units: Query<(
+        Entity,
+        &Transform,
+        &UnitInformation,
+        &mut OrderQueue,
+        &mut Movable,
+        Option<&Stats>,
+        Option<&Engaging>,
+    )>,
+    others: Query<(Entity, &Transform, &UnitInformation)>,
+    mut commands: Commands,
+) {
+    for (entity, transform, unit_information, mut order_queue, mut movable, stats, engaging) in
+        units.iter_mut()
+    {
+        if !matches!(order_queue.current(), Some(Order::AttackMove(_))) || !order_queue.started {
+            // Units which got other orders in the meantime are no longer engaging
+            if engaging.is_some() {
+                commands.entity(entity).remove::<Engaging>();
+            }
+            continue;
+        }
+        let sensor_range: f32 = stats
+            .and_then(|stats| stats.get(StatId::SensorRange))
+            .unwrap_or(0.0);
+        let position: Vec3 = transform.translation;
+        let hostile: Option<Entity> = others
+            .iter()
+            .filter(|(_, other, information)| {
+                information.civilisation != unit_information.civilisation
+                    && other.translation.distance(position) <= sensor_range
+            })
+            .min_by(|(_, a, _), (_, b, _)| {
+                a.translation
+                    .distance(position)
+                    .total_cmp(&b.translation.distance(position))
+            })
+            .map(|(hostile, ..)| hostile);
+        match (hostile, engaging) {
+            (Some(hostile), None) => {
+                movable.speed = 0.0;
+                halt(&mut commands.entity(entity));
+                commands.entity(entity).insert(Engaging(hostile));
+            }
+            (Some(hostile), Some(engaging)) if engaging.0 != hostile => {
+                commands.entity(entity).insert(Engaging(hostile));
+            }
+            (None, Some(_)) => {
+                // Restart the order from where the unit stopped
+                order_queue.started = false;
+                commands.entity(entity).remove::<Engaging>();
+            }
+            _ => {}
+        }
+    }
+}
+/// Lines from every selected unit through the targets of its queued orders, patrols are drawn
+/// as closed loops
+fn draw_order_lines(units: Query<(&Transform, &OrderQueue), With<Selected>>, mut gizmos: Gizmos) {
+    for (transform, order_queue) in units.iter() {
+        let height: f32 = transform.translation.y;
+        let mut from: Vec3 = transform.translation;
+        for order in order_queue.orders() {
+            let to: Vec3 = Vec3::new(order.target().x, height, order.target().y);
+            gizmos.line(from, to, order.line_color());
+            from = to;
+        }
+        let first_patrol: Option<&Order> = order_queue
+            .orders()
+            .find(|order| matches!(order, Order::Patrol(_)));
+        if let (Some(first), Some(Order::Patrol(_))) = (first_patrol, order_queue.orders().last()) {
+            let to: Vec3 = Vec3::new(first.target().x, height, first.target().y);
+            gizmos.line(from, to, PATROL_LINE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathfinding::PathError;
+    use crate::player_controller::Civilisation;
+    use crate::spawner::UnitType;
+    use bevy::utils::HashMap;
+
+    fn order_test_app() -> App {
+        let mut app: App = App::new();
+        app.add_event::<GroupMoveCommand>()
+            .add_event::<PathFailed>()
+            .add_systems(
+                Update,
+                (engage_hostiles, advance_orders.after(engage_hostiles)),
+            );
+        app
+    }
+
+    #[test]
+    fn queued_orders_run_in_order() {
+        let mut order_queue: OrderQueue = OrderQueue::default();
+        order_queue.replace(Order::Move(Vec2::X), Vec2::ZERO);
+        order_queue.push(Order::AttackMove(Vec2::Y));
+        assert_eq!(order_queue.next_to_start(), Some(Order::Move(Vec2::X)));
+        order_queue.started = true;
+        assert_eq!(order_queue.next_to_start(), None);
+        order_queue.finish_current();
+        assert_eq!(
+            order_queue.next_to_start(),
+            Some(Order::AttackMove(Vec2::Y))
+        );
+        order_queue.started = true;
+        order_queue.finish_current();
+        assert_eq!(order_queue.current(), None);
+    }
+
+    #[test]
+    fn patrols_cycle() {
+        let mut order_queue: OrderQueue = OrderQueue::default();
+        order_queue.replace(Order::Patrol(Vec2::X), Vec2::ZERO);
+        order_queue.push(Order::Patrol(Vec2::Y));
+        let mut visited: Vec<Vec2> = Vec::new();
+        for _ in 0..6 {
+            let order: Order = order_queue.next_to_start().unwrap();
+            visited.push(order.target());
+            order_queue.started = true;
+            order_queue.finish_current();
+        }
+        assert_eq!(
+            visited,
+            vec![Vec2::X, Vec2::ZERO, Vec2::Y, Vec2::X, Vec2::ZERO, Vec2::Y]
+        );
+    }
+
+    #[test]
+    fn lone_patrol_point_is_dropped() {
+        let mut order_queue: OrderQueue = OrderQueue::default();
+        order_queue.push(Order::Patrol(Vec2::X));
+        order_queue.started = true;
+        order_queue.finish_current();
+        assert_eq!(order_queue.current(), None);
+    }
+
+    #[test]
+    fn unreachable_patrol_points_are_dropped() {
+        let mut app: App = order_test_app();
+        let mut order_queue: OrderQueue = OrderQueue::default();
+        order_queue.replace(Order::Patrol(Vec2::X), Vec2::ZERO);
+        order_queue.push(Order::Patrol(Vec2::Y));
+        order_queue.started = true;
+        let unit: Entity = app.world_mut().spawn(order_queue).id();
+        app.world_mut().send_event(PathFailed {
+            entity: unit,
+            target: Vec2::X,
+            error: PathError::Unreachable(UVec2::ONE),
+        });
+        app.update();
+        let order_queue: &OrderQueue = app.world().get::<OrderQueue>(unit).unwrap();
+        assert_eq!(
+            order_queue.orders().copied().collect::<Vec<Order>>(),
+            vec![Order::Patrol(Vec2::ZERO), Order::Patrol(Vec2::Y)]
+        );
+        assert_eq!(
+            app.world()
+                .get::<MoveCommand>(unit)
+                .map(|command| command.target),
+            Some(Vec2::ZERO)
+        );
+    }
+
+    #[test]
+    fn attack_moves_resume_without_hostiles_in_range() {
+        let mut app: App = order_test_app();
+        let hostile: Entity = app.world_mut().spawn(Transform::default()).id();
+        let mut order_queue: OrderQueue = OrderQueue::default();
+        order_queue.replace(Order::AttackMove(Vec2::X), Vec2::ZERO);
+        order_queue.started = true;
+        let unit: Entity = app
+            .world_mut()
+            .spawn((
+                Transform::default(),
+                UnitInformation {
+                    unit_name: String::new(),
+                    unit_type: UnitType::Cruiser,
+                    civilisation: Civilisation::Greek,
+                    thumbnail: String::new(),
+                    unit_info: String::new(),
+                    unit_cost: HashMap::new(),
+                },
+                order_queue,
+                Movable::default(),
+                Engaging(hostile),
+            ))
+            .id();
+        app.update();
+        assert!(app.world().get::<Engaging>(unit).is_none());
+        assert_eq!(
+            app.world()
+                .get::<MoveCommand>(unit)
+                .map(|command| command.target),
+            Some(Vec2::X)
+        );
+    }
+
+    #[test]
+    fn replacing_and_clearing_drop_queued_orders() {
+        let mut order_queue: OrderQueue = OrderQueue::default();
+        order_queue.push(Order::Move(Vec2::X));
+        order_queue.push(Order::Move(Vec2::Y));
+        order_queue.started = true;
+        order_queue.replace(Order::Move(Vec2::ONE), Vec2::ZERO);
+        assert_eq!(order_queue.orders().count(), 1);
+        assert_eq!(order_queue.next_to_start(), Some(Order::Move(Vec2::ONE)));
+        order_queue.clear();
+        assert_eq!(order_queue.current(), None);
+    }
+}
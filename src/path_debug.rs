@@ -0,0 +1,203 @@
+use crate::a_star::AStarParams;
+use crate::environment::MovementGrid;
+use crate::flow_field::FlowFieldFollower;
+use crate::hpa_star::HierarchicalRoute;
+use crate::movable::{MoveCommand, MovementPath};
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use std::f32::consts::FRAC_PI_2;
+
+const BLOCKED_CELL: Color = Color::srgb(0.8, 0.1, 0.1);
+const OPEN_CELL: Color = Color::srgb(0.9, 0.9, 0.2);
+const CLOSED_CELL: Color = Color::srgb(0.5, 0.5, 0.5);
+const PATH_LINE: Color = Color::srgb(0.2, 0.9, 0.9);
+const TARGET_MARKER: Color = Color::srgb(1.0, 0.5, 0.0);
+/// Drawn cells are slightly smaller than the grid, so neighbouring ones stay apart
+const CELL_SCALE: f32 = 0.8;
+
+#[derive(Resource)]
+pub struct PathDebugSettings {
+    pub enabled: bool,
+    pub toggle: KeyCode,
+    /// Blocked cells are drawn up to this many cells around the point below the camera
+    pub grid_radius: u32,
+    /// Height of the plane blocked cells are drawn on
+    pub grid_height: f32,
+}
+impl Default for PathDebugSettings {
+    fn default() -> Self {
+        PathDebugSettings {
+            enabled: false,
+            toggle: KeyCode::F3,
+            grid_radius: 50,
+            grid_height: 2.0,
+        }
+    }
+}
+
+/// Draws the movement grid, running searches, paths and targets of units with gizmos
+pub struct PathDebug;
+impl Plugin for PathDebug {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PathDebugSettings>().add_systems(
+            Update,
+            (
+                toggle_path_debug,
+                (draw_blocked_cells, draw_searches, draw_paths, draw_targets)
+                    .run_if(path_debug_enabled),
+            ),
+        );
+    }
+}
+fn path_debug_enabled(path_debug_settings: Res<PathDebugSettings>) -> bool {
+    path_debug_settings.enabled
+}
+fn toggle_path_debug(
+    key_input: Res<ButtonInput<KeyCode>>,
+    mut path_debug_settings: ResMut<PathDebugSettings>,
+) {
+    if key_input.just_pressed(path_debug_settings.toggle) {
+        path_debug_settings.enabled = !path_debug_settings.enabled;
+        info!("Path debugging enabled: {}", path_debug_settings.enabled);
+    }
+}
+/// Outline of a grid cell lying on the xz plane at the given height
+fn draw_cell(gizmos: &mut Gizmos, grid: &MovementGrid, cell: UVec2, height: f32, color: Color) {
+    let center: Vec2 = grid.cell_center(cell);
+    gizmos.rect(
+        Vec3::new(center.x, height, center.y),
+        Quat::from_rotation_x(FRAC_PI_2),
+        Vec2::splat(grid.settings.cell_size * CELL_SCALE),
+        color,
+    );
+}
+fn draw_blocked_cells(
+    cameras: Query<&GlobalTransform, With<Camera3d>>,
+    gridmap: Res<MovementGrid>,
+    path_debug_settings: Res<PathDebugSettings>,
+    mut gizmos: Gizmos,
+) {
+    for camera in cameras.iter() {
+        let Some(center) = gridmap.clamped_cell_at(camera.translation().xz()) else {
+            continue;
+        };
+        let radius: UVec2 = UVec2::splat(path_debug_settings.grid_radius);
+        let min: UVec2 = center.saturating_sub(radius);
+        let max: UVec2 = (center + radius).min(UVec2::new(
+            gridmap.width() as u32 - 1,
+            gridmap.height() as u32 - 1,
+        ));
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                let cell: UVec2 = UVec2 { x, y };
+                if !gridmap.is_free(cell) {
+                    draw_cell(
+                        &mut gizmos,
+                        &gridmap,
+                        cell,
+                        path_debug_settings.grid_height,
+                        BLOCKED_CELL,
+                    );
+                }
+            }
+        }
+    }
+}
+/// Open and closed sets of the searches which are still running
+fn draw_searches(
+    searches: Query<(&Transform, &AStarParams)>,
+    gridmap: Res<MovementGrid>,
+    mut gizmos: Gizmos,
+) {
+    for (transform, params) in searches.iter() {
+        let height: f32 = transform.translation.y;
+        let closed: HashSet<UVec2> = params.search.closed_cells().collect();
+        let open: HashSet<UVec2> = params
+            .search
+            .open_cells()
+            .filter(|cell| !closed.contains(cell))
+            .collect();
+        for &cell in &closed {
+            draw_cell(&mut gizmos, &gridmap, cell, height, CLOSED_CELL);
+        }
+        for &cell in &open {
+            draw_cell(&mut gizmos, &gridmap, cell, height, OPEN_CELL);
+        }
+    }
+}
+/// Remaining paths with an arrow for the heading of every node
+fn draw_paths(
+    paths: Query<(&Transform, &MovementPath)>,
+    gridmap: Res<MovementGrid>,
+    mut gizmos: Gizmos,
+) {
+    let arrow_length: f32 = gridmap.settings.cell_size * 2.0;
+    for (transform, movement_path) in paths.iter() {
+        let height: f32 = transform.translation.y;
+        // The next node is the last one
+        let nodes: Vec<Vec3> = movement_path
+            .path
+            .iter()
+            .rev()
+            .map(|node| Vec3::new(node.xy.x, height, node.xy.y))
+            .collect();
+        gizmos.linestrip(
+            std::iter::once(transform.translation).chain(nodes.iter().copied()),
+            PATH_LINE,
+        );
+        for (node, &position) in movement_path.path.iter().rev().zip(&nodes) {
+            let direction: Vec2 = node.h.direction() * arrow_length;
+            gizmos.arrow(
+                position,
+                position + Vec3::new(direction.x, 0.0, direction.y),
+                PATH_LINE,
+            );
+        }
+    }
+}
+/// Marks where every moving unit is headed in the end
+fn draw_targets(
+    units: Query<(
+        &Transform,
+        Option<&MoveCommand>,
+        Option<&HierarchicalRoute>,
+        Option<&FlowFieldFollower>,
+        Option<&AStarParams>,
+        Option<&MovementPath>,
+    )>,
+    gridmap: Res<MovementGrid>,
+    mut gizmos: Gizmos,
+) {
+    let radius: f32 = gridmap.settings.cell_size * 2.0;
+    for (transform, move_command, route, follower, params, movement_path) in units.iter() {
+        let target: Option<Vec2> = move_command
+            .map(|move_command| move_command.target)
+            .or_else(|| {
+                route
+                    .and_then(|route| route.waypoints.first())
+                    .map(|&cell| gridmap.cell_center(cell))
+            })
+            .or_else(|| follower.map(|follower| follower.slot))
+            .or_else(|| params.map(|params| gridmap.cell_center(params.search.target())))
+            .or_else(|| {
+                movement_path
+                    .and_then(|movement_path| movement_path.path.first())
+                    .map(|node| node.xy)
+            });
+        let Some(target) = target else {
+            continue;
+        };
+        let position: Vec3 = Vec3::new(target.x, transform.translation.y, target.y);
+        gizmos.circle(position, Dir3::Y, radius, TARGET_MARKER);
+        gizmos.line(
+            position - Vec3::X * radius,
+            position + Vec3::X * radius,
+            TARGET_MARKER,
+        );
+        gizmos.line(
+            position - Vec3::Z * radius,
+            position + Vec3::Z * radius,
+            TARGET_MARKER,
+        );
+    }
+}
@@ -0,0 +1,856 @@
+use crate::environment::MovementGrid;
+use bevy::math::{IVec2, UVec2, Vec2};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::error::Error;
+use std::f32::consts::TAU;
+use std::fmt;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+/// Path costs are integers, the factor keeps diagonal steps more expensive than straight ones
+pub const DISTANCE_FACTOR: f32 = 10.0;
+/// Turning radius in cells for searches without a unit to take it from
+pub const DEFAULT_TURNING_RADIUS: f32 = 1.0;
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+pub struct NodeCoords {
+    pub xy: UVec2,
+    pub h: Option<Heading>,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathNode {
+    pub xy: Vec2,
+    pub h: Heading,
+}
+#[derive(Eq, PartialEq, Hash, Clone, Copy, EnumIter, Debug, Default)]
+pub enum Heading {
+    #[default]
+    N,
+    NNE,
+    NE,
+    NEE,
+    E,
+    SEE,
+    SE,
+    SSE,
+    S,
+    SSW,
+    SW,
+    SWW,
+    W,
+    NWW,
+    NW,
+    NNW,
+}
+impl Heading {
+    /// Unit vector pointing along the heading, the inverse of `heading_towards`
+    pub fn direction(&self) -> Vec2 {
+        let count: usize = Heading::iter().count();
+        let index: usize = Heading::iter()
+            .position(|heading| heading == *self)
+            .unwrap_or(0);
+        let angle: f32 = index as f32 * TAU / count as f32;
+        Vec2::new(-angle.sin(), angle.cos())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    StartOutOfGrid(UVec2),
+    GoalOutOfGrid(UVec2),
+    GoalBlocked(UVec2),
+    Unreachable(UVec2),
+}
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::StartOutOfGrid(cell) => write!(f, "start {} is outside of the grid", cell),
+            PathError::GoalOutOfGrid(cell) => write!(f, "goal {} is outside of the grid", cell),
+            PathError::GoalBlocked(cell) => write!(f, "goal {} is blocked", cell),
+            PathError::Unreachable(cell) => write!(f, "no path to {}", cell),
+        }
+    }
+}
+impl Error for PathError {}
+
+/// Finds the cheapest path from the start cell to the goal cell, taking course changes into
+/// account. The path is in travel order and excludes the start cell.
+pub fn find_path(
+    grid: &MovementGrid,
+    start: UVec2,
+    start_heading: Heading,
+    goal: UVec2,
+    turning_radius: f32,
+) -> Result<Vec<PathNode>, PathError> {
+    let mut search: PathSearch = PathSearch::new(grid, start, start_heading, goal, turning_radius)?;
+    let mut budget: usize = usize::MAX;
+    match search.expand(grid, &mut budget) {
+        SearchProgress::Found(end) => Ok(search.path(end, grid)),
+        SearchProgress::Searching | SearchProgress::Exhausted => Err(PathError::Unreachable(goal)),
+    }
+}
+
+/// State of an A* search which can be advanced a limited number of nodes at a time
+pub struct PathSearch {
+    nodes: HashMap<NodeCoords, SearchNode>,
+    open_set: BinaryHeap<OpenNode>,
+    closed_set: HashSet<NodeCoords>,
+    start: UVec2,
+    target: UVec2,
+    /// In cells, the wider it is the more course changes cost
+    turning_radius: f32,
+}
+#[derive(Clone, Copy, Debug)]
+struct SearchNode {
+    g_score: i32,
+    came_from: Option<NodeCoords>,
+}
+/// Entry of the open list. A node may be pushed again with a better score, the outdated
+/// entries are skipped once the node is closed.
+#[derive(Eq, PartialEq)]
+struct OpenNode {
+    f_score: i32,
+    g_score: i32,
+    coords: NodeCoords,
+}
+impl Ord for OpenNode {
+    // BinaryHeap is a max heap, so the lowest f score has to compare as the greatest.
+    // Ties go to the node furthest along, which tends to reach the target sooner.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .f_score
+            .cmp(&self.f_score)
+            .then_with(|| self.g_score.cmp(&other.g_score))
+    }
+}
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+pub enum SearchProgress {
+    Searching,
+    Found(NodeCoords),
+    Exhausted,
+}
+impl PathSearch {
+    pub fn new(
+        grid: &MovementGrid,
+        start: UVec2,
+        start_heading: Heading,
+        goal: UVec2,
+        turning_radius: f32,
+    ) -> Result<Self, PathError> {
+        if !is_on_grid(grid, start) {
+            return Err(PathError::StartOutOfGrid(start));
+        }
+        if !is_on_grid(grid, goal) {
+            return Err(PathError::GoalOutOfGrid(goal));
+        }
+        if !grid.is_free(goal) {
+            return Err(PathError::GoalBlocked(goal));
+        }
+        let start: NodeCoords = NodeCoords {
+            xy: start,
+            h: Some(start_heading),
+        };
+        Ok(PathSearch {
+            nodes: HashMap::from([(
+                start,
+                SearchNode {
+                    g_score: 0,
+                    came_from: None,
+                },
+            )]),
+            open_set: BinaryHeap::from([OpenNode {
+                f_score: heuristic(start, goal),
+                g_score: 0,
+                coords: start,
+            }]),
+            closed_set: HashSet::new(),
+            start: start.xy,
+            target: goal,
+            turning_radius,
+        })
+    }
+    pub fn start(&self) -> UVec2 {
+        self.start
+    }
+    pub fn target(&self) -> UVec2 {
+        self.target
+    }
+    /// True if the search found a way to the cell, coming from any direction
+    pub fn has_reached(&self, cell: UVec2) -> bool {
+        Heading::iter().any(|heading| {
+            self.nodes.contains_key(&NodeCoords {
+                xy: cell,
+                h: Some(heading),
+            })
+        })
+    }
+    /// Cells with nodes waiting to be expanded
+    pub fn open_cells(&self) -> impl Iterator<Item = UVec2> + '_ {
+        self.open_set
+            .iter()
+            .filter(|node| !self.closed_set.contains(&node.coords))
+            .map(|node| node.coords.xy)
+    }
+    /// Cells with nodes which have been expanded
+    pub fn closed_cells(&self) -> impl Iterator<Item = UVec2> + '_ {
+        self.closed_set.iter().map(|node| node.xy)
+    }
+    /// Expands nodes until the target is found, the open set runs dry or the budget is used up
+    pub fn expand(&mut self, grid: &MovementGrid, budget: &mut usize) -> SearchProgress {
+        while *budget > 0 {
+            let Some(OpenNode {
+                g_score,
+                coords: current,
+                ..
+            }) = self.open_set.pop()
+            else {
+                return SearchProgress::Exhausted;
+            };
+            if !self.closed_set.insert(current) {
+                continue;
+            }
+            *budget -= 1;
+            if current.xy == self.target {
+                return SearchProgress::Found(current);
+            }
+            for neighbour in get_neighbours(current.xy, grid) {
+                if self.closed_set.contains(&neighbour) {
+                    continue;
+                }
+                // Huge turning radii saturate the step cost instead of overflowing the score
+                let tentative_g_score: i32 = g_score.saturating_add(
+                    (inertia_based_inter_cell_movement(current, neighbour, self.turning_radius)
+                        * DISTANCE_FACTOR) as i32,
+                );
+                if self
+                    .nodes
+                    .get(&neighbour)
+                    .is_some_and(|node| node.g_score <= tentative_g_score)
+                {
+                    continue;
+                }
+                self.nodes.insert(
+                    neighbour,
+                    SearchNode {
+                        g_score: tentative_g_score,
+                        came_from: Some(current),
+                    },
+                );
+                self.open_set.push(OpenNode {
+                    f_score: tentative_g_score.saturating_add(heuristic(neighbour, self.target)),
+                    g_score: tentative_g_score,
+                    coords: neighbour,
+                });
+            }
+        }
+        SearchProgress::Searching
+    }
+    /// Path to a found node in travel order, without the start cell
+    pub fn path(&self, end: NodeCoords, grid: &MovementGrid) -> Vec<PathNode> {
+        let mut total_path: Vec<PathNode> = vec![];
+        let mut current: NodeCoords = end;
+        while let Some(previous) = self.nodes.get(&current).and_then(|node| node.came_from) {
+            total_path.push(PathNode {
+                xy: grid.cell_center(current.xy),
+                h: current.h.unwrap_or_default(),
+            });
+            current = previous;
+        }
+        total_path.reverse();
+        total_path
+    }
+}
+fn is_on_grid(grid: &MovementGrid, cell: UVec2) -> bool {
+    (cell.x as usize) < grid.width() && (cell.y as usize) < grid.height()
+}
+fn heuristic(from: NodeCoords, target: UVec2) -> i32 {
+    (heuristical_distance(
+        from,
+        NodeCoords {
+            xy: target,
+            h: None,
+        },
+    ) * DISTANCE_FACTOR) as i32
+}
+
+fn calculate_course_deflection(start: &NodeCoords, end: &NodeCoords) -> u32 {
+    let difference: i32 = (start.h.unwrap() as i32 - end.h.unwrap() as i32).abs();
+    let half_headings: i32 = (Heading::iter().len() as f32 / 2.0).ceil() as i32;
+    (half_headings - (difference - half_headings).abs()) as u32
+}
+/// Length of the step plus a penalty for turning onto its heading. The penalty grows with the
+/// square of the course change, so a wide turn over several steps is cheaper than a sharp one.
+pub fn inertia_based_inter_cell_movement(
+    from: NodeCoords,
+    to: NodeCoords,
+    turning_radius: f32,
+) -> f32 {
+    let course_deflection: f32 = calculate_course_deflection(&from, &to) as f32;
+    let deflection_angle: f32 = course_deflection * TAU / Heading::iter().len() as f32;
+    let cost: f32 =
+        from.xy.as_vec2().distance(to.xy.as_vec2()) + deflection_angle.powi(2) * turning_radius;
+    cost
+}
+pub fn heuristical_distance(from: NodeCoords, to: NodeCoords) -> f32 {
+    from.xy.as_vec2().distance(to.xy.as_vec2())
+}
+/// Free cell closest to the given one, searching square rings of growing size up to
+/// `max_distance` cells away. Within the first ring with a free cell the closest one is taken.
+pub fn nearest_free_cell(grid: &MovementGrid, cell: UVec2, max_distance: u32) -> Option<UVec2> {
+    let center: IVec2 = cell.as_ivec2();
+    for distance in 0..=max_distance as i32 {
+        let ring = (-distance..=distance).flat_map(|offset| {
+            [
+                IVec2::new(offset, -distance),
+                IVec2::new(offset, distance),
+                IVec2::new(-distance, offset),
+                IVec2::new(distance, offset),
+            ]
+        });
+        let nearest: Option<IVec2> = ring
+            .map(|offset| center + offset)
+            .filter(|candidate| {
+                candidate.cmpge(IVec2::ZERO).all()
+                    && is_on_grid(grid, candidate.as_uvec2())
+                    && grid.is_free(candidate.as_uvec2())
+            })
+            .min_by_key(|candidate| candidate.distance_squared(center));
+        if let Some(nearest) = nearest {
+            return Some(nearest.as_uvec2());
+        }
+    }
+    None
+}
+/// Heading of a step between two cells. Decreasing x points east, increasing y north, so a
+/// knight move of (-1, 2) heads NNE.
+pub fn calculate_heading(from: &UVec2, to: &UVec2) -> Heading {
+    heading_towards(from.as_vec2(), to.as_vec2())
+}
+/// Heading closest to the direction between two points, using the same orientation as
+/// `calculate_heading`
+pub fn heading_towards(from: Vec2, to: Vec2) -> Heading {
+    let direction: Vec2 = to - from;
+    let headings: Vec<Heading> = Heading::iter().collect();
+    let angle: f32 = (-direction.x).atan2(direction.y).rem_euclid(TAU);
+    let index: usize = (angle / TAU * headings.len() as f32).round() as usize % headings.len();
+    headings[index]
+}
+/// True if every cell within `clearance` cells around the given cell is on the grid and free
+fn is_clear(grid: &MovementGrid, cell: IVec2, clearance: u32) -> bool {
+    let clearance: i32 = clearance as i32;
+    (cell.x - clearance..=cell.x + clearance).all(|x| {
+        (cell.y - clearance..=cell.y + clearance).all(|y| {
+            x >= 0
+                && y >= 0
+                && (x as usize) < grid.width()
+                && (y as usize) < grid.height()
+                && grid.is_free(UVec2::new(x as u32, y as u32))
+        })
+    })
+}
+/// True if a unit keeping `clearance` cells to obstacles can move straight between the centres
+/// of the two cells. Every cell the line touches is checked. Like `check_path_width`, passing
+/// exactly through a corner needs one of the two cells beside it to be free.
+pub fn line_of_sight(grid: &MovementGrid, from: UVec2, to: UVec2, clearance: u32) -> bool {
+    let direction: Vec2 = to.as_vec2() - from.as_vec2();
+    let step: IVec2 = (to.as_ivec2() - from.as_ivec2()).signum();
+    let t_delta: Vec2 = Vec2::ONE / direction.abs();
+    let mut t_max: Vec2 = t_delta / 2.0;
+    let mut cell: IVec2 = from.as_ivec2();
+    loop {
+        if !is_clear(grid, cell, clearance) {
+            return false;
+        }
+        if cell == to.as_ivec2() {
+            return true;
+        }
+        if t_max.x + f32::EPSILON < t_max.y {
+            cell.x += step.x;
+            t_max.x += t_delta.x;
+        } else if t_max.y + f32::EPSILON < t_max.x {
+            cell.y += step.y;
+            t_max.y += t_delta.y;
+        } else {
+            if !is_clear(grid, cell + IVec2::new(step.x, 0), clearance)
+                && !is_clear(grid, cell + IVec2::new(0, step.y), clearance)
+            {
+                return false;
+            }
+            cell += step;
+            t_max += t_delta;
+        }
+    }
+}
+/// Drops every node which can be skipped by moving straight from the previous remaining one
+/// (string pulling), then points each node's heading along its straight segment.
+/// `path` is in travel order and starts after `start`.
+pub fn smooth_path(
+    grid: &MovementGrid,
+    start: UVec2,
+    path: &[PathNode],
+    clearance: u32,
+) -> Vec<PathNode> {
+    let cells: Vec<UVec2> = path
+        .iter()
+        .filter_map(|node| grid.cell_at(node.xy))
+        .collect();
+    if cells.len() != path.len() {
+        return path.to_vec();
+    }
+    let mut smoothed: Vec<PathNode> = Vec::new();
+    let mut anchor: UVec2 = start;
+    let mut anchor_position: Vec2 = grid.cell_center(start);
+    for (index, node) in path.iter().enumerate() {
+        let is_last: bool = index + 1 == path.len();
+        if !is_last && line_of_sight(grid, anchor, cells[index + 1], clearance) {
+            continue;
+        }
+        smoothed.push(PathNode {
+            xy: node.xy,
+            h: heading_towards(anchor_position, node.xy),
+        });
+        anchor = cells[index];
+        anchor_position = node.xy;
+    }
+    smoothed
+}
+pub fn check_path_width(current: UVec2, target: UVec2, gridmap: &MovementGrid) -> bool {
+    if current.x != target.x
+        && current.y != target.y
+        && gridmap.grid[current.x as usize][target.y as usize] != 0
+        && gridmap.grid[target.x as usize][current.y as usize] != 0
+    {
+        return false;
+    }
+
+    true
+}
+/// Adjacent cells and the cells a knight move away, which together cover all 16 headings.
+/// Knight moves need both cells they pass to be free.
+pub fn get_neighbours(current: UVec2, gridmap: &MovementGrid) -> Vec<NodeCoords> {
+    let mut neighbours: Vec<NodeCoords> = Vec::new();
+    for x in -2i32..3 {
+        for y in -2i32..3 {
+            let knight_move: bool = x.abs() + y.abs() == 3;
+            if !knight_move && (x.abs() > 1 || y.abs() > 1) {
+                continue;
+            }
+            let adjacent_cell: IVec2 = IVec2 {
+                x: current.x as i32 + x,
+                y: current.y as i32 + y,
+            };
+
+            if adjacent_cell.x >= 0
+                && (adjacent_cell.x as usize) < gridmap.grid.len()
+                && adjacent_cell.y >= 0
+                && (adjacent_cell.y as usize) < gridmap.grid[0].len()
+                && gridmap.grid[adjacent_cell.x as usize][adjacent_cell.y as usize] == 0
+                && adjacent_cell.as_uvec2() != current
+                && if knight_move {
+                    line_of_sight(gridmap, current, adjacent_cell.as_uvec2(), 0)
+                } else {
+                    check_path_width(current, adjacent_cell.as_uvec2(), gridmap)
+                }
+            {
+                neighbours.push(NodeCoords {
+                    xy: UVec2 {
+                        x: adjacent_cell.x as u32,
+                        y: adjacent_cell.y as u32,
+                    },
+                    h: Some(calculate_heading(&current, &adjacent_cell.as_uvec2())),
+                });
+            }
+        }
+    }
+    neighbours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(path: &[PathNode]) -> Vec<UVec2> {
+        path.iter().map(|node| node.xy.as_uvec2()).collect()
+    }
+    fn node(x: u32, y: u32, h: Heading) -> NodeCoords {
+        NodeCoords {
+            xy: UVec2 { x, y },
+            h: Some(h),
+        }
+    }
+
+    #[test]
+    fn straight_path_keeps_heading() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["....", "....", "....", "...."]);
+        let path: Vec<PathNode> = find_path(
+            &grid,
+            UVec2::new(1, 0),
+            Heading::N,
+            UVec2::new(1, 3),
+            DEFAULT_TURNING_RADIUS,
+        )
+        .unwrap();
+        assert_eq!(
+            cells(&path),
+            vec![UVec2::new(1, 1), UVec2::new(1, 2), UVec2::new(1, 3)]
+        );
+        assert!(path.iter().all(|node| node.h == Heading::N));
+    }
+
+    #[test]
+    fn path_to_start_is_empty() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["..", ".."]);
+        assert_eq!(
+            find_path(
+                &grid,
+                UVec2::new(1, 1),
+                Heading::N,
+                UVec2::new(1, 1),
+                DEFAULT_TURNING_RADIUS
+            ),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn path_avoids_obstacles() {
+        let grid: MovementGrid = MovementGrid::from_rows(&[".....", "###..", "....."]);
+        let path: Vec<PathNode> = find_path(
+            &grid,
+            UVec2::new(0, 0),
+            Heading::N,
+            UVec2::new(0, 2),
+            DEFAULT_TURNING_RADIUS,
+        )
+        .unwrap();
+        assert_eq!(path.last().unwrap().xy.as_uvec2(), UVec2::new(0, 2));
+        for cell in cells(&path) {
+            assert!(grid.is_free(cell), "path crosses blocked cell {}", cell);
+        }
+        // The wall has to be passed on its open end
+        assert!(cells(&path).iter().any(|cell| cell.y == 1 && cell.x >= 3));
+    }
+
+    #[test]
+    fn diagonal_steps_do_not_squeeze_between_blocked_corners() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["....", "#...", ".#..", "...."]);
+        let neighbours: Vec<UVec2> = get_neighbours(UVec2::new(0, 2), &grid)
+            .iter()
+            .map(|neighbour| neighbour.xy)
+            .collect();
+        assert!(!neighbours.contains(&UVec2::new(1, 1)));
+        assert!(!check_path_width(UVec2::new(0, 2), UVec2::new(1, 1), &grid));
+        assert!(check_path_width(UVec2::new(1, 2), UVec2::new(2, 1), &grid));
+    }
+
+    #[test]
+    fn neighbours_stay_on_the_grid() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["...", "...", "..."]);
+        assert_eq!(get_neighbours(UVec2::new(0, 0), &grid).len(), 5);
+        assert_eq!(get_neighbours(UVec2::new(2, 2), &grid).len(), 5);
+        assert_eq!(get_neighbours(UVec2::new(1, 0), &grid).len(), 7);
+        assert_eq!(get_neighbours(UVec2::new(1, 1), &grid).len(), 8);
+    }
+
+    #[test]
+    fn knight_moves_cover_all_headings() {
+        let open: MovementGrid =
+            MovementGrid::from_rows(&[".....", ".....", ".....", ".....", "....."]);
+        let headings: HashSet<Heading> = get_neighbours(UVec2::new(2, 2), &open)
+            .iter()
+            .filter_map(|neighbour| neighbour.h)
+            .collect();
+        assert_eq!(headings.len(), 16);
+        assert_eq!(
+            calculate_heading(&UVec2::new(2, 2), &UVec2::new(1, 4)),
+            Heading::NNE
+        );
+        assert_eq!(
+            calculate_heading(&UVec2::new(2, 2), &UVec2::new(4, 1)),
+            Heading::SWW
+        );
+        // A knight move may not jump over a blocked cell
+        let pillar: MovementGrid = MovementGrid::from_rows(&["...", ".#.", "..."]);
+        let neighbours: Vec<UVec2> = get_neighbours(UVec2::new(1, 0), &pillar)
+            .iter()
+            .map(|neighbour| neighbour.xy)
+            .collect();
+        assert!(!neighbours.contains(&UVec2::new(0, 2)));
+        assert!(!neighbours.contains(&UVec2::new(2, 2)));
+    }
+
+    #[test]
+    fn wide_turning_circles_avoid_sharp_turns() {
+        // Reversing course: a small turning radius turns on the spot, a large one swings out
+        let grid: MovementGrid =
+            MovementGrid::from_rows(&[".........", ".........", ".........", "........."]);
+        let start: UVec2 = UVec2::new(4, 2);
+        let goal: UVec2 = UVec2::new(4, 0);
+        let tight: Vec<PathNode> = find_path(&grid, start, Heading::N, goal, 0.1).unwrap();
+        let wide: Vec<PathNode> = find_path(&grid, start, Heading::N, goal, 3.0).unwrap();
+        assert_eq!(cells(&tight), vec![UVec2::new(4, 1), goal]);
+        assert!(wide.len() > tight.len());
+        assert!(cells(&wide).iter().any(|cell| cell.y > start.y));
+    }
+
+    #[test]
+    fn endless_turning_radius_does_not_overflow() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["....", "....", "....", "...."]);
+        let path: Result<Vec<PathNode>, PathError> = find_path(
+            &grid,
+            UVec2::new(0, 0),
+            Heading::S,
+            UVec2::new(3, 3),
+            f32::INFINITY,
+        );
+        assert_eq!(
+            path.map(|path| path.last().map(|node| node.xy)),
+            Ok(Some(Vec2::new(3.5, 3.5)))
+        );
+    }
+
+    #[test]
+    fn path_along_grid_edge() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["...", "##.", "..."]);
+        let path: Vec<PathNode> = find_path(
+            &grid,
+            UVec2::new(0, 0),
+            Heading::N,
+            UVec2::new(0, 2),
+            DEFAULT_TURNING_RADIUS,
+        )
+        .unwrap();
+        assert!(cells(&path).contains(&UVec2::new(2, 1)));
+        assert_eq!(path.last().unwrap().xy.as_uvec2(), UVec2::new(0, 2));
+    }
+
+    #[test]
+    fn course_changes_add_costs() {
+        let cost = |from: Heading, turning_radius: f32| {
+            inertia_based_inter_cell_movement(
+                node(1, 1, from),
+                node(1, 2, Heading::N),
+                turning_radius,
+            )
+        };
+        assert_eq!(cost(Heading::N, 1.0), 1.0);
+        assert!(cost(Heading::N, 1.0) < cost(Heading::NNE, 1.0));
+        assert!(cost(Heading::NNE, 1.0) < cost(Heading::NE, 1.0));
+        assert!(cost(Heading::NE, 1.0) < cost(Heading::S, 1.0));
+        // Wider turning circles make the same course change more expensive
+        assert_eq!(cost(Heading::N, 5.0), 1.0);
+        assert!(cost(Heading::NE, 1.0) < cost(Heading::NE, 5.0));
+        assert_eq!(
+            calculate_course_deflection(&node(0, 0, Heading::NW), &node(0, 0, Heading::N)),
+            2
+        );
+        assert_eq!(
+            calculate_course_deflection(&node(0, 0, Heading::NNW), &node(0, 0, Heading::NNE)),
+            2
+        );
+        assert_eq!(
+            calculate_course_deflection(&node(0, 0, Heading::E), &node(0, 0, Heading::W)),
+            8
+        );
+    }
+
+    #[test]
+    fn start_heading_changes_the_route() {
+        // Two equally long routes around the pillar, the one in the current heading is cheaper
+        let grid: MovementGrid = MovementGrid::from_rows(&["...", ".#.", "..."]);
+        let start: UVec2 = UVec2::new(1, 0);
+        let goal: UVec2 = UVec2::new(1, 2);
+        let towards_west: Vec<UVec2> =
+            cells(&find_path(&grid, start, Heading::W, goal, DEFAULT_TURNING_RADIUS).unwrap());
+        let towards_east: Vec<UVec2> =
+            cells(&find_path(&grid, start, Heading::E, goal, DEFAULT_TURNING_RADIUS).unwrap());
+        // Headings are mirrored on the x axis, west lies towards increasing x
+        assert_eq!(towards_west.first().map(|cell| cell.x), Some(2));
+        assert_eq!(towards_east.first().map(|cell| cell.x), Some(0));
+    }
+
+    #[test]
+    fn unreachable_goal() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["....", "####", "...."]);
+        assert_eq!(
+            find_path(
+                &grid,
+                UVec2::new(0, 0),
+                Heading::N,
+                UVec2::new(3, 2),
+                DEFAULT_TURNING_RADIUS
+            ),
+            Err(PathError::Unreachable(UVec2::new(3, 2)))
+        );
+    }
+
+    #[test]
+    fn blocked_goals_fall_back_to_the_nearest_free_cell() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["......", ".###..", ".####.", ".###.."]);
+        assert_eq!(
+            nearest_free_cell(&grid, UVec2::new(0, 0), 3),
+            Some(UVec2::new(0, 0))
+        );
+        assert_eq!(
+            nearest_free_cell(&grid, UVec2::new(3, 2), 3),
+            Some(UVec2::new(4, 1))
+        );
+        let nearest: UVec2 = nearest_free_cell(&grid, UVec2::new(2, 2), 3).unwrap();
+        assert!(grid.is_free(nearest));
+        assert_eq!(nearest.as_vec2().distance(Vec2::new(2.0, 2.0)), 2.0);
+        assert_eq!(nearest_free_cell(&grid, UVec2::new(2, 2), 0), None);
+    }
+
+    #[test]
+    fn targets_outside_the_grid_are_clamped() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["....", "....", "...."]);
+        assert_eq!(grid.cell_at(Vec2::new(-3.0, 1.5)), None);
+        assert_eq!(
+            grid.clamped_cell_at(Vec2::new(-3.0, 1.5)),
+            Some(UVec2::new(0, 1))
+        );
+        assert_eq!(
+            grid.clamped_cell_at(Vec2::new(10.0, 10.0)),
+            Some(UVec2::new(3, 2))
+        );
+    }
+
+    #[test]
+    fn invalid_cells_are_rejected() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["..", ".#"]);
+        assert_eq!(
+            find_path(
+                &grid,
+                UVec2::new(0, 0),
+                Heading::N,
+                UVec2::new(1, 1),
+                DEFAULT_TURNING_RADIUS
+            ),
+            Err(PathError::GoalBlocked(UVec2::new(1, 1)))
+        );
+        assert_eq!(
+            find_path(
+                &grid,
+                UVec2::new(0, 0),
+                Heading::N,
+                UVec2::new(2, 0),
+                DEFAULT_TURNING_RADIUS
+            ),
+            Err(PathError::GoalOutOfGrid(UVec2::new(2, 0)))
+        );
+        assert_eq!(
+            find_path(
+                &grid,
+                UVec2::new(0, 5),
+                Heading::N,
+                UVec2::new(0, 1),
+                DEFAULT_TURNING_RADIUS
+            ),
+            Err(PathError::StartOutOfGrid(UVec2::new(0, 5)))
+        );
+    }
+
+    #[test]
+    fn open_ground_is_crossed_in_one_segment() {
+        let grid: MovementGrid = MovementGrid::from_rows(&["......", "......", "......", "......"]);
+        let start: UVec2 = UVec2::new(0, 0);
+        let path: Vec<PathNode> = find_path(
+            &grid,
+            start,
+            Heading::N,
+            UVec2::new(5, 3),
+            DEFAULT_TURNING_RADIUS,
+        )
+        .unwrap();
+        let smoothed: Vec<PathNode> = smooth_path(&grid, start, &path, 0);
+        assert_eq!(smoothed.len(), 1);
+        assert_eq!(smoothed[0].xy, path.last().unwrap().xy);
+        assert_eq!(
+            smoothed[0].h,
+            heading_towards(grid.cell_center(start), smoothed[0].xy)
+        );
+    }
+
+    #[test]
+    fn smoothing_keeps_corners_around_obstacles() {
+        let grid: MovementGrid =
+            MovementGrid::from_rows(&[".....", ".....", "####.", ".....", "....."]);
+        let start: UVec2 = UVec2::new(0, 0);
+        let path: Vec<PathNode> = find_path(
+            &grid,
+            start,
+            Heading::N,
+            UVec2::new(0, 4),
+            DEFAULT_TURNING_RADIUS,
+        )
+        .unwrap();
+        let smoothed: Vec<PathNode> = smooth_path(&grid, start, &path, 0);
+        assert!(smoothed.len() > 1 && smoothed.len() < path.len());
+        let mut from: UVec2 = start;
+        for node in &smoothed {
+            let to: UVec2 = node.xy.as_uvec2();
+            assert!(line_of_sight(&grid, from, to, 0));
+            from = to;
+        }
+    }
+
+    #[test]
+    fn line_of_sight_respects_walls_and_clearance() {
+        let grid: MovementGrid =
+            MovementGrid::from_rows(&[".......", ".......", ".......", "...#...", "....#.."]);
+        assert!(line_of_sight(&grid, UVec2::new(0, 0), UVec2::new(6, 1), 0));
+        assert!(!line_of_sight(&grid, UVec2::new(0, 3), UVec2::new(6, 3), 0));
+        assert!(line_of_sight(&grid, UVec2::new(0, 1), UVec2::new(6, 1), 0));
+        assert!(!line_of_sight(&grid, UVec2::new(1, 2), UVec2::new(5, 2), 1));
+        // Passing through a corner between two blocked cells is not allowed
+        assert!(!line_of_sight(&grid, UVec2::new(3, 4), UVec2::new(4, 3), 0));
+        assert!(line_of_sight(&grid, UVec2::new(3, 2), UVec2::new(4, 3), 0));
+    }
+
+    #[test]
+    fn partial_searches_expose_open_and_closed_cells() {
+        let grid: MovementGrid =
+            MovementGrid::from_rows(&[".....", ".....", ".....", ".....", "....."]);
+        let mut search: PathSearch = PathSearch::new(
+            &grid,
+            UVec2::new(0, 0),
+            Heading::N,
+            UVec2::new(4, 4),
+            DEFAULT_TURNING_RADIUS,
+        )
+        .unwrap();
+        let mut budget: usize = 3;
+        assert!(matches!(
+            search.expand(&grid, &mut budget),
+            SearchProgress::Searching
+        ));
+        let closed: HashSet<UVec2> = search.closed_cells().collect();
+        assert!(closed.contains(&UVec2::new(0, 0)));
+        assert!(search.open_cells().count() > 0);
+        assert!(search.open_cells().all(|cell| search.has_reached(cell)));
+    }
+
+    #[test]
+    fn heading_directions_round_trip() {
+        for heading in Heading::iter() {
+            assert_eq!(heading_towards(Vec2::ZERO, heading.direction()), heading);
+        }
+        assert!(Heading::E.direction().abs_diff_eq(Vec2::NEG_X, 1e-6));
+    }
+
+    #[test]
+    fn headings_follow_directions() {
+        let origin: Vec2 = Vec2::ZERO;
+        assert_eq!(heading_towards(origin, Vec2::new(0.0, 3.0)), Heading::N);
+        assert_eq!(heading_towards(origin, Vec2::new(-2.0, 0.1)), Heading::E);
+        assert_eq!(heading_towards(origin, Vec2::new(1.0, -1.0)), Heading::SW);
+        assert_eq!(
+            heading_towards(origin, Vec2::new(1.0, 1.0)),
+            calculate_heading(&UVec2::ZERO, &UVec2::ONE)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use crate::environment::MovementGrid;
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use std::f32::consts::TAU;
+
+#[derive(Resource)]
+pub struct PlacementSettings {
+    /// How far from the requested location a free spot is searched for
+    pub search_radius: f32,
+    /// Extra distance kept to other colliders
+    pub margin: f32,
+}
+impl Default for PlacementSettings {
+    fn default() -> Self {
+        PlacementSettings {
+            search_radius: 10.0,
+            margin: 0.1,
+        }
+    }
+}
+
+/// Searches rings of increasing size around the requested location for the first spot where a
+/// unit of the given radius neither overlaps a collider or a blocked grid cell nor a unit which
+/// was placed earlier in the same frame and is not known to the physics world yet.
+pub fn find_free_location(
+    requested: Vec3,
+    radius: f32,
+    settings: &PlacementSettings,
+    rapier_context: &RapierContext,
+    movement_grid: &MovementGrid,
+    placed_this_frame: &[(Vec3, f32)],
+) -> Option<Vec3> {
+    let clearance: f32 = radius + settings.margin;
+    let shape: Collider = Collider::ball(clearance);
+    let step: f32 = (2.0 * clearance).max(movement_grid.settings.cell_size);
+    let rings: u32 = (settings.search_radius / step).ceil() as u32;
+    for ring in 0..=rings {
+        let ring_radius: f32 = ring as f32 * step;
+        let samples: u32 = ((TAU * ring_radius / step).ceil() as u32).max(1);
+        for sample in 0..samples {
+            let angle: f32 = TAU * sample as f32 / samples as f32;
+            let candidate: Vec3 =
+                requested + Vec3::new(angle.cos(), 0.0, angle.sin()) * ring_radius;
+            if !movement_grid.is_area_free(candidate.xz(), clearance) {
+                continue;
+            }
+            if placed_this_frame.iter().any(|(location, other_radius)| {
+                location.distance(candidate) < clearance + other_radius
+            }) {
+                continue;
+            }
+            if rapier_context
+                .intersection_with_shape(
+                    candidate,
+                    Quat::IDENTITY,
+                    &shape,
+                    QueryFilter::new().exclude_sensors(),
+                )
+                .is_some()
+            {
+                continue;
+            }
+            return Some(candidate);
+        }
+    }
+    None
+}
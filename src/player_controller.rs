@@ -1,4 +1,5 @@
-use crate::movable::{Movable, MoveCommand};
+use crate::movable::{halt, Movable};
+use crate::orders::{order_for_click, Order, OrderQueue, OrderSettings};
 use crate::ownable::{Selectable, Selected, SelectionCircle};
 use crate::spawner::UnitType;
 use crate::ui::RayBlock;
@@ -19,7 +20,7 @@ use bevy_rapier3d::prelude::*;
 pub enum TechLevel {
     L0,
 }
-#[derive(Eq, Hash, PartialEq, Clone, Copy)]
+#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
 pub enum Civilisation {
     Greek,
     // ROMAN,
@@ -313,15 +314,16 @@ fn asset_loaded(
     }
 }
 
-fn mouse_controller(
+pub fn mouse_controller(
     mut selectable: Query<(Entity, &mut Selectable, &Children)>,
     mut selection_circle: Query<&mut Visibility, With<SelectionCircle>>,
     mut selected_entities: Query<(Entity, &Selected)>,
-    mut movables: Query<Entity, (With<Selected>, With<Movable>)>,
+    mut movables: Query<(Entity, &Transform, &mut OrderQueue), (With<Selected>, With<Movable>)>,
     mut commands: Commands,
     mut ray_hit_event: EventReader<RayHit>,
     deselect_event: EventReader<DeselectEvent>,
     key_input: Res<ButtonInput<KeyCode>>,
+    order_settings: Res<OrderSettings>,
 ) {
     if !deselect_event.is_empty() {
         println!("Deselection");
@@ -369,10 +371,15 @@ fn mouse_controller(
                 x: hit.ray_intersection.point.x,
                 y: hit.ray_intersection.point.z,
             };
-
-            for entity in movables.iter_mut() {
-                commands.entity(entity).remove::<MoveCommand>();
-                commands.entity(entity).insert(MoveCommand { target });
+            let order: Order = order_for_click(&key_input, &order_settings, target);
+            let queued: bool = key_input.pressed(order_settings.queue);
+            for (entity, transform, mut order_queue) in movables.iter_mut() {
+                if queued {
+                    order_queue.push(order);
+                } else {
+                    halt(&mut commands.entity(entity));
+                    order_queue.replace(order, transform.translation.xz());
+                }
             }
         }
     }
@@ -0,0 +1,252 @@
+use crate::{
+    ownable::Selected,
+    player_controller::{Civilisation, ContextMenuAction, LocalPlayer, PlayerInfo, RayHit},
+    resources::{ResourceStockpiles, ResourceType},
+    spawner::{InstanceSpawnRequest, UnitInformation, UnitSpecifications, UnitType},
+    unit_loader::{update_unit_specifications, UnitSpecificationChanged},
+};
+use bevy::{prelude::*, utils::HashMap};
+use std::collections::VecDeque;
+
+/// Offset of the initial rally point from the producing building
+const DEFAULT_RALLY_OFFSET: Vec3 = Vec3::new(2.0, 0.0, 1.0);
+/// Height at which units are placed
+const UNIT_PLANE_HEIGHT: f32 = 2.0;
+
+pub struct ProductionOrder {
+    pub unit_type: UnitType,
+    pub remaining: f32,
+    pub player: Entity,
+    pub paid: HashMap<ResourceType, i32>,
+}
+/// Units a building is going to produce. Only the first order is worked on.
+#[derive(Component)]
+pub struct ProductionQueue {
+    pub orders: VecDeque<ProductionOrder>,
+    pub rally_point: Vec3,
+}
+#[derive(Event)]
+pub struct ProductionRequest {
+    pub building: Entity,
+    pub player: Entity,
+    pub unit_type: UnitType,
+}
+/// Cancels the most recently queued order and refunds its cost
+#[derive(Event)]
+pub struct ProductionCancelRequest {
+    pub building: Entity,
+}
+#[derive(Resource)]
+pub struct ProductionSettings {
+    /// Cancels the last order of the selected buildings
+    pub cancel: KeyCode,
+}
+impl Default for ProductionSettings {
+    fn default() -> Self {
+        ProductionSettings {
+            cancel: KeyCode::Backspace,
+        }
+    }
+}
+
+pub struct Production;
+impl Plugin for Production {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ProductionSettings>()
+            .add_event::<ProductionRequest>()
+            .add_event::<ProductionCancelRequest>()
+            .add_systems(
+                Update,
+                (
+                    update_build_actions.after(update_unit_specifications),
+                    add_production_queues.after(update_build_actions),
+                    set_rally_point,
+                    cancel_key,
+                    enqueue_production.after(add_production_queues),
+                    cancel_production.after(cancel_key),
+                    advance_production
+                        .after(enqueue_production)
+                        .after(cancel_production),
+                ),
+            );
+    }
+}
+/// Turns the `produces` list of every unit file into build actions of the players of that
+/// civilisation, so the context menu offers them
+fn update_build_actions(
+    mut player_infos: Query<&mut PlayerInfo>,
+    unit_specifications: Res<UnitSpecifications>,
+) {
+    if !unit_specifications.is_changed() {
+        return;
+    }
+    for mut player_info in player_infos.iter_mut() {
+        let civilisation = player_info.civilisation;
+        for ((unit_civilisation, unit_type), unit_specification) in
+            &unit_specifications.unit_specifications
+        {
+            if *unit_civilisation != civilisation {
+                continue;
+            }
+            if unit_specification.produces.is_empty() {
+                player_info.context_menu_actions.remove(unit_type);
+            } else {
+                player_info.context_menu_actions.insert(
+                    unit_type.clone(),
+                    unit_specification
+                        .produces
+                        .iter()
+                        .cloned()
+                        .map(ContextMenuAction::Build)
+                        .collect(),
+                );
+            }
+        }
+    }
+}
+/// Every unit of the local player's civilisation with build actions gets a production queue,
+/// when it spawns or once its unit file gains a `produces` list
+fn add_production_queues(
+    mut commands: Commands,
+    mut changed_events: EventReader<UnitSpecificationChanged>,
+    units: Query<(Entity, Ref<UnitInformation>, &Transform), Without<ProductionQueue>>,
+    player_info: Query<&PlayerInfo, With<LocalPlayer>>,
+) {
+    let changed_types: Vec<(Civilisation, UnitType)> = changed_events
+        .read()
+        .map(|changed_event| (changed_event.civilisation, changed_event.unit_type.clone()))
+        .collect();
+    let Ok(player_info) = player_info.get_single() else {
+        return;
+    };
+    for (entity, unit_information, transform) in units.iter() {
+        let changed: bool = changed_types.iter().any(|(civilisation, unit_type)| {
+            *civilisation == unit_information.civilisation
+                && *unit_type == unit_information.unit_type
+        });
+        // Like the build actions, buildings belong to the players of their civilisation
+        if (!unit_information.is_added() && !changed)
+            || unit_information.civilisation != player_info.civilisation
+        {
+            continue;
+        }
+        let can_build: bool = player_info
+            .context_menu_actions
+            .get(&unit_information.unit_type)
+            .is_some_and(|actions| {
+                actions
+                    .iter()
+                    .any(|action| matches!(action, ContextMenuAction::Build(_)))
+            });
+        if can_build {
+            let mut rally_point: Vec3 = transform.translation + DEFAULT_RALLY_OFFSET;
+            rally_point.y = UNIT_PLANE_HEIGHT;
+            commands.entity(entity).insert(ProductionQueue {
+                orders: VecDeque::new(),
+                rally_point,
+            });
+        }
+    }
+}
+fn enqueue_production(
+    mut production_requests: EventReader<ProductionRequest>,
+    mut buildings: Query<(&mut ProductionQueue, &UnitInformation)>,
+    mut players: Query<(&mut ResourceStockpiles, &PlayerInfo)>,
+    unit_specifications: Res<UnitSpecifications>,
+) {
+    for request in production_requests.read() {
+        let Ok((mut production_queue, building_information)) = buildings.get_mut(request.building)
+        else {
+            continue;
+        };
+        let Some(unit_specification) = unit_specifications
+            .unit_specifications
+            .get(&(building_information.civilisation, request.unit_type.clone()))
+        else {
+            warn!("No specification for {}", request.unit_type);
+            continue;
+        };
+        let Ok((mut stockpile, player_info)) = players.get_mut(request.player) else {
+            continue;
+        };
+        if player_info.civilisation != building_information.civilisation {
+            warn!("Player can not build in a building of another civilisation");
+            continue;
+        }
+        match stockpile.pay(&unit_specification.unit_cost) {
+            Some(paid) => production_queue.orders.push_back(ProductionOrder {
+                unit_type: request.unit_type.clone(),
+                remaining: unit_specification.build_time,
+                player: request.player,
+                paid,
+            }),
+            None => info!("Not enough resources to build {}", request.unit_type),
+        }
+    }
+}
+fn cancel_key(
+    key_input: Res<ButtonInput<KeyCode>>,
+    production_settings: Res<ProductionSettings>,
+    selected_buildings: Query<Entity, (With<Selected>, With<ProductionQueue>)>,
+    mut cancel_requests: EventWriter<ProductionCancelRequest>,
+) {
+    if key_input.just_pressed(production_settings.cancel) {
+        for building in selected_buildings.iter() {
+            cancel_requests.send(ProductionCancelRequest { building });
+        }
+    }
+}
+fn cancel_production(
+    mut cancel_requests: EventReader<ProductionCancelRequest>,
+    mut buildings: Query<&mut ProductionQueue>,
+    mut stockpiles: Query<&mut ResourceStockpiles>,
+) {
+    for request in cancel_requests.read() {
+        let Ok(mut production_queue) = buildings.get_mut(request.building) else {
+            continue;
+        };
+        if let Some(order) = production_queue.orders.pop_back() {
+            if let Ok(mut stockpile) = stockpiles.get_mut(order.player) {
+                stockpile.refund(&order.paid);
+            }
+        }
+    }
+}
+/// Right clicking with a building selected moves its rally point
+fn set_rally_point(
+    mut ray_hit_event: EventReader<RayHit>,
+    mut selected_buildings: Query<&mut ProductionQueue, With<Selected>>,
+) {
+    for hit in ray_hit_event.read() {
+        if hit.mouse_unit_move_button {
+            for mut production_queue in selected_buildings.iter_mut() {
+                production_queue.rally_point = Vec3 {
+                    x: hit.ray_intersection.point.x,
+                    y: UNIT_PLANE_HEIGHT,
+                    z: hit.ray_intersection.point.z,
+                };
+            }
+        }
+    }
+}
+fn advance_production(
+    time: Res<Time>,
+    mut buildings: Query<(&mut ProductionQueue, &UnitInformation)>,
+    mut spawn_events: EventWriter<InstanceSpawnRequest>,
+) {
+    for (mut production_queue, building_information) in buildings.iter_mut() {
+        let rally_point: Vec3 = production_queue.rally_point;
+        let Some(order) = production_queue.orders.front_mut() else {
+            continue;
+        };
+        order.remaining -= time.delta_seconds();
+        if order.remaining <= 0.0 {
+            spawn_events.send(InstanceSpawnRequest {
+                location: rally_point,
+                unit_type: order.unit_type.clone(),
+                civilisation: building_information.civilisation,
+            });
+            production_queue.orders.pop_front();
+        }
+    }
+}
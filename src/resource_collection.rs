@@ -1,13 +1,21 @@
 use crate::{
-    civilisation::CivilisationBoniMap,
+    move_requests::{
+        report_requested_moves, start_requested_moves, MoveArrived, MoveCancelled, MoveRequest,
+        MoveRequester, RequestedMove,
+    },
+    orders::OrderQueue,
     ownable::Selected,
-    player_controller::{LocalPlayer, PlayerInfo, RayHit},
+    player_controller::{mouse_controller, LocalPlayer, RayHit},
     resources::{ResourceLevel, ResourceStockpiles, ResourceType},
-    spawner::{EntityWrapper, UnitInformation, UnitStat, UnitType},
+    spawner::{EntityWrapper, UnitInformation, UnitSpecifications},
+    stats::{StatId, Stats},
 };
 
 use bevy::{prelude::*, time::Stopwatch};
 
+/// Part of the mining distance collectors close in to, leaving some slack for the pathfinding
+const APPROACH_FRACTION: f32 = 0.8;
+
 //#[derive(Resource)]
 //struct CollectionTick {
 //    time: Stopwatch,
@@ -29,48 +37,135 @@ struct Collector {
 pub struct ResourceCollection;
 impl Plugin for ResourceCollection {
     fn build(&self, app: &mut App) {
-        app.add_event::<RayHit>()
-            .add_systems(Update, (process_collection_command, collect));
+        app.add_event::<RayHit>().add_systems(
+            Update,
+            (
+                process_collection_command
+                    .after(mouse_controller)
+                    .before(start_requested_moves),
+                finish_approach.after(report_requested_moves),
+                collect,
+            ),
+        );
     }
 }
 
+/// Closest point to the collector within mining distance of the resource, the collector's own
+/// position if it is already close enough
+fn approach_point(collector: Vec2, resource: Vec2, max_mining_dist: f32) -> Vec2 {
+    resource + (collector - resource).clamp_length_max(max_mining_dist * APPROACH_FRACTION)
+}
+
 fn process_collection_command(
     mut commands: Commands,
-    selected_entities: Query<(Entity, &UnitInformation), With<Selected>>,
+    mut selected_entities: Query<
+        (
+            Entity,
+            &UnitInformation,
+            &Transform,
+            Option<&Stats>,
+            Option<&mut OrderQueue>,
+        ),
+        With<Selected>,
+    >,
     mut ray_hit_event: EventReader<RayHit>,
-    resource_sources: Query<&ResourceLevel>,
+    resource_sources: Query<(&ResourceLevel, &Transform)>,
+    mut move_requests: EventWriter<MoveRequest>,
     main_player: Query<Entity, With<LocalPlayer>>,
+    unit_specifications: Res<UnitSpecifications>,
 ) {
     let main_player_entity: Entity = main_player.get_single().unwrap();
     for hit in ray_hit_event.read() {
-        if let Ok(resource_level) = resource_sources.get(hit.hit_entity) {
-            for (entity, unit_information) in selected_entities.iter() {
-                match unit_information.unit_type {
-                    UnitType::MiningStation => {
-                        commands.entity(entity).insert(Collector {
-                            resource: resource_level.resource_type, //TODO make adaptive
-                            resource_entity: EntityWrapper {
-                                entity: hit.hit_entity,
-                            },
+        if !hit.mouse_unit_move_button {
+            continue;
+        }
+        if let Ok((resource_level, resource_transform)) = resource_sources.get(hit.hit_entity) {
+            for (entity, unit_information, transform, stats, order_queue) in
+                selected_entities.iter_mut()
+            {
+                let is_collector: bool = unit_specifications
+                    .unit_specifications
+                    .get(&(
+                        unit_information.civilisation,
+                        unit_information.unit_type.clone(),
+                    ))
+                    .is_some_and(|unit_specification| unit_specification.collector);
+                if is_collector {
+                    commands.entity(entity).insert(Collector {
+                        resource: resource_level.resource_type, //TODO make adaptive
+                        resource_entity: EntityWrapper {
+                            entity: hit.hit_entity,
+                        },
 
-                            player: EntityWrapper {
-                                entity: main_player_entity,
-                            },
-                            collecting: CollectorState::Approaching,
-                        });
+                        player: EntityWrapper {
+                            entity: main_player_entity,
+                        },
+                        collecting: CollectorState::Approaching,
+                    });
+                    // The click is a collection order rather than a move to the resource
+                    if let Some(mut order_queue) = order_queue {
+                        order_queue.clear();
                     }
-                    _ => {}
+                    let max_mining_dist: f32 = stats
+                        .and_then(|stats| stats.get(StatId::MaxMiningDist))
+                        .unwrap_or(0.0);
+                    move_requests.send(MoveRequest {
+                        entity,
+                        target: approach_point(
+                            transform.translation.xz(),
+                            resource_transform.translation.xz(),
+                            max_mining_dist,
+                        ),
+                        requester: MoveRequester::Collection,
+                    });
                 }
             }
         }
     }
 }
 
+/// Collectors start collecting once they arrived at their resource, and give up if they did not
+/// get close enough or were sent elsewhere
+fn finish_approach(
+    mut arrivals: EventReader<MoveArrived>,
+    mut cancellations: EventReader<MoveCancelled>,
+    mut collectors: Query<(&mut Collector, &Transform, &Stats, Has<RequestedMove>)>,
+    resource_location: Query<&Transform, With<ResourceLevel>>,
+    mut commands: Commands,
+) {
+    for arrival in arrivals
+        .read()
+        .filter(|arrival| arrival.requester == MoveRequester::Collection)
+    {
+        let Ok((mut collector, transform, stats, _)) = collectors.get_mut(arrival.entity) else {
+            continue;
+        };
+        collector.collecting =
+            check_collection_state(&collector, transform, &resource_location, stats);
+        if collector.collecting == CollectorState::Approaching {
+            warn!("Collector could not get within mining distance");
+            commands.entity(arrival.entity).remove::<Collector>();
+        }
+    }
+    for cancellation in cancellations
+        .read()
+        .filter(|cancellation| cancellation.requester == MoveRequester::Collection)
+    {
+        // Collectors sent to another resource are already on their next move
+        if collectors
+            .get(cancellation.entity)
+            .is_ok_and(|(.., requested)| !requested)
+        {
+            commands.entity(cancellation.entity).remove::<Collector>();
+        }
+    }
+}
+
 fn check_collection_state(
     collector: &Collector,
     collector_transform: &Transform,
     resource_location: &Query<&Transform, With<ResourceLevel>>,
-    unit_information: &UnitInformation,
+    stats: &Stats,
 ) -> CollectorState {
     let mut dist = 0.0;
     if let Ok(resource_transform) = resource_location.get(collector.resource_entity.entity) {
@@ -79,12 +174,7 @@ fn check_collection_state(
             .distance(resource_transform.translation);
     }
 
-    let mut max_mining_dist: f32 = 0.0;
-    for stat in &unit_information.stats.0 {
-        if let UnitStat::MaxMiningDist(m) = stat {
-            max_mining_dist = *m;
-        }
-    }
+    let max_mining_dist: f32 = stats.get(StatId::MaxMiningDist).unwrap_or(0.0);
     if collector.collecting != CollectorState::Approaching && max_mining_dist < dist {
         return CollectorState::Cancelled;
     } else if collector.collecting == CollectorState::Approaching && max_mining_dist > dist {
@@ -95,54 +185,28 @@ fn check_collection_state(
 
 fn collect(
     time: Res<Time>,
-    mut collectors: Query<(Entity, &mut Collector, &Transform, &UnitInformation)>,
+    mut collectors: Query<(Entity, &mut Collector, &Transform, &Stats)>,
     mut resource_levels: Query<&mut ResourceStockpiles>,
     resource_location: Query<&Transform, With<ResourceLevel>>,
     mut stopwatch: Local<Stopwatch>,
     // mut resource_update_events: EventWriter<UIResourceUpdateEvent>,
     mut commands: Commands,
-    player_infos: Query<&PlayerInfo>,
-    civilisation_boni_map: Res<CivilisationBoniMap>,
 ) {
     stopwatch.tick(time.delta());
     if stopwatch.elapsed().as_secs() >= 1 {
         stopwatch.reset();
-        for (collector_entity, mut collector, collector_transform, unit_information) in
-            collectors.iter_mut()
-        {
-            collector.collecting = check_collection_state(
-                &collector,
-                collector_transform,
-                &resource_location,
-                unit_information,
-            );
-            // Calculate collection rate
-            let mut rate = 0.0;
-            for stat in &unit_information.stats.0 {
-                match stat {
-                    UnitStat::BaseMiningRate(bmr) => rate += *bmr,
-                    UnitStat::BonusMiningRate((t, r)) => {
-                        if *t == collector.resource {
-                            rate += r
-                        }
-                    }
-                    _ => {}
-                }
-            }
+        for (collector_entity, mut collector, collector_transform, stats) in collectors.iter_mut() {
+            collector.collecting =
+                check_collection_state(&collector, collector_transform, &resource_location, stats);
+            // Calculate collection rate, civilisation boni are part of the bonus mining rate
+            let rate: f32 = stats.get(StatId::BaseMiningRate).unwrap_or(0.0)
+                + stats
+                    .get(StatId::BonusMiningRate(collector.resource))
+                    .unwrap_or(0.0);
             if rate <= 0.0 {
                 collector.collecting = CollectorState::Cancelled;
                 println!("Collector apparantly incapable of mining resources");
             }
-            let player_info = player_infos.get(collector.player.entity).unwrap();
-            let civilisation_boni = civilisation_boni_map
-                .map
-                .get(&player_info.civilisation)
-                .unwrap();
-            for (t, r) in &civilisation_boni.eco_boni.resource_boni {
-                if *t == collector.resource {
-                    rate += r;
-                }
-            }
             // End
             if collector.collecting == CollectorState::Collecting {
                 match resource_levels.get_mut(collector.player.entity) {
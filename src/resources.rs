@@ -1,6 +1,7 @@
 use bevy::{prelude::*, utils::HashMap};
 use serde::{Deserialize, Serialize};
-#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug, Serialize, Deserialize)]
+use strum_macros::EnumIter;
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug, Serialize, Deserialize, EnumIter)]
 pub enum ResourceType {
     Plotanium,
 }
@@ -16,6 +17,27 @@ impl ResourceStockpiles {
     pub fn get(&self, resource_type: &ResourceType) -> Option<&i32> {
         self.0.get(resource_type)
     }
+    /// Deducts the cost if every resource is available, returning what was actually taken
+    pub fn pay(&mut self, cost: &HashMap<ResourceType, f32>) -> Option<HashMap<ResourceType, i32>> {
+        let cost: HashMap<ResourceType, i32> = cost
+            .iter()
+            .map(|(resource_type, amount)| (*resource_type, amount.ceil() as i32))
+            .collect();
+        for (resource_type, amount) in &cost {
+            if *self.get(resource_type).unwrap_or(&0) < *amount {
+                return None;
+            }
+        }
+        for (resource_type, amount) in &cost {
+            *self.0.entry(*resource_type).or_insert(0) -= amount;
+        }
+        Some(cost)
+    }
+    pub fn refund(&mut self, paid: &HashMap<ResourceType, i32>) {
+        for (resource_type, amount) in paid {
+            *self.0.entry(*resource_type).or_insert(0) += amount;
+        }
+    }
 }
 
 #[derive(Component)]
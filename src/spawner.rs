@@ -1,16 +1,30 @@
 use crate::{
+    environment::MovementGrid,
+    mesh_collider::{is_mesh_shape, MeshColliders, PendingMeshCollider},
     movable::Movable,
+    obstacles::GridObstacle,
+    orders::OrderQueue,
     ownable::{Selectable, SelectionCircle},
+    placement::{find_free_location, PlacementSettings},
     player_controller::{Civilisation, RenderLayerMap},
     resources::ResourceType,
+    stats::Stats,
+    unit_loader::{
+        update_unit_specifications, UnitSpecificationHandles, UnitSpecificationLoaderPlugin,
+    },
     utils::ShapeTypeSerializable,
 };
-use bevy::{prelude::*, render::view::RenderLayers, utils::HashMap};
+use bevy::{
+    prelude::*,
+    render::view::RenderLayers,
+    utils::{HashMap, HashSet},
+};
 use bevy_rapier3d::{prelude::*, rapier::prelude::ShapeType};
 use serde::{Deserialize, Serialize};
 use std::ops::{Deref, DerefMut};
 // use std::collections::HashMap;
 use std::fmt;
+use std::str::FromStr;
 // Create some sort of unit map with regards to civ
 impl fmt::Display for Civilisation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -19,11 +33,27 @@ impl fmt::Display for Civilisation {
         }
     }
 }
+impl FromStr for Civilisation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "greek" => Ok(Civilisation::Greek),
+            _ => Err(format!("Unknown civilisation {}", s)),
+        }
+    }
+}
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub enum UnitStat {
     MaxMiningDist(f32),
     BaseMiningRate(f32),
     BonusMiningRate((ResourceType, f32)),
+    Speed(f32),
+    Acceleration(f32),
+    Deceleration(f32),
+    TurnRate(f32),
+    Hull(f32),
+    SensorRange(f32),
 }
 #[derive(Clone, Serialize, Deserialize)]
 pub struct UnitStats(pub Vec<UnitStat>);
@@ -41,11 +71,45 @@ impl DerefMut for UnitStats {
     }
 }
 
-#[derive(Eq, Hash, PartialEq, Clone)]
+/// Unit types are identified by a string id in the unit files. The built-in ones get their own
+/// variant, every other id declared in a unit file becomes a `Custom` type.
+#[derive(Eq, Hash, PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
 pub enum UnitType {
     Cruiser,
     Spacestation,
     MiningStation,
+    Custom(String),
+}
+impl UnitType {
+    pub const BUILT_IN: [UnitType; 3] = [
+        UnitType::Cruiser,
+        UnitType::Spacestation,
+        UnitType::MiningStation,
+    ];
+    pub fn id(&self) -> &str {
+        match self {
+            UnitType::Cruiser => "Cruiser",
+            UnitType::Spacestation => "Spacestation",
+            UnitType::MiningStation => "MiningStation",
+            UnitType::Custom(id) => id,
+        }
+    }
+}
+impl From<String> for UnitType {
+    fn from(id: String) -> Self {
+        match id.as_str() {
+            "Cruiser" => UnitType::Cruiser,
+            "Spacestation" => UnitType::Spacestation,
+            "MiningStation" => UnitType::MiningStation,
+            _ => UnitType::Custom(id),
+        }
+    }
+}
+impl From<UnitType> for String {
+    fn from(unit_type: UnitType) -> Self {
+        unit_type.id().to_string()
+    }
 }
 impl fmt::Display for UnitType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -53,9 +117,36 @@ impl fmt::Display for UnitType {
             UnitType::Cruiser => write!(f, "Cruiser"),
             UnitType::Spacestation => write!(f, "Space Station"),
             UnitType::MiningStation => write!(f, "Mining Station"),
+            UnitType::Custom(id) => write!(f, "{}", id),
+        }
+    }
+}
+/// Every unit type known to the game, the built-in ones and those declared by unit files
+#[derive(Resource)]
+pub struct UnitTypeRegistry {
+    unit_types: HashSet<UnitType>,
+}
+impl Default for UnitTypeRegistry {
+    fn default() -> Self {
+        UnitTypeRegistry {
+            unit_types: UnitType::BUILT_IN.into_iter().collect(),
         }
     }
 }
+impl UnitTypeRegistry {
+    /// Returns true if the unit type was not known before
+    pub fn register(&mut self, unit_type: UnitType) -> bool {
+        self.unit_types.insert(unit_type)
+    }
+    /// Forgets a unit type which no unit file declares anymore, built-in ones are kept. Returns
+    /// true if the unit type was known before.
+    pub fn unregister(&mut self, unit_type: &UnitType) -> bool {
+        !UnitType::BUILT_IN.contains(unit_type) && self.unit_types.remove(unit_type)
+    }
+    pub fn contains(&self, unit_type: &UnitType) -> bool {
+        self.unit_types.contains(unit_type)
+    }
+}
 
 #[derive(Resource)]
 pub struct UnitSpecifications {
@@ -69,20 +160,77 @@ pub struct UnitSpecification {
     pub icon_path: String,
     pub unit_name: String,
     pub movable: bool,
+    /// Whether the unit can be ordered to mine resources
+    #[serde(default)]
+    pub collector: bool,
+    /// Unit types this unit can produce, a producer gets a production queue
+    #[serde(default)]
+    pub produces: Vec<UnitType>,
     pub shape: ShapeTypeSerializable,
     pub dimensions: Vec3,
+    /// Only used by the Round* shapes
+    #[serde(default)]
+    pub border_radius: f32,
+    /// Only used by the Compound shape
+    #[serde(default)]
+    pub compound: Vec<ColliderPart>,
     pub prescaling: f32,
     pub base_stats: UnitStats,
     pub unit_info: String,
     pub unit_cost: HashMap<ResourceType, f32>,
+    /// Seconds a building needs to produce this unit
+    #[serde(default)]
+    pub build_time: f32,
+}
+/// One sub shape of a compound collider, placed relative to the unit's origin
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ColliderPart {
+    pub shape: ShapeTypeSerializable,
+    pub dimensions: Vec3,
+    #[serde(default)]
+    pub border_radius: f32,
+    #[serde(default)]
+    pub offset: Vec3,
+    #[serde(default)]
+    pub rotation: Quat,
+    #[serde(default)]
+    pub compound: Vec<ColliderPart>,
+}
+pub const SELECTION_TEXTURE: &str = "textures/selection_texture.png";
+/// Edge length of the selection circle plane, in model space like `dimensions`
+pub fn selection_circle_size(unit_specification: &UnitSpecification) -> f32 {
+    2.5 * unit_specification.dimensions.max_element()
 }
 pub struct InstanceSpawner;
-#[derive(Event)]
+#[derive(Event, Clone)]
 pub struct InstanceSpawnRequest {
     pub location: Vec3,
     pub unit_type: UnitType,
     pub civilisation: Civilisation,
 }
+#[derive(Debug, Clone)]
+pub enum SpawnFailureReason {
+    UnknownUnit,
+    UnsupportedShape(ShapeType),
+    NoFreeSpace,
+}
+impl fmt::Display for SpawnFailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnFailureReason::UnknownUnit => write!(f, "No specification for this unit"),
+            SpawnFailureReason::UnsupportedShape(shape) => {
+                write!(f, "Shape {:?} not supported", shape)
+            }
+            SpawnFailureReason::NoFreeSpace => write!(f, "No free space near the location"),
+        }
+    }
+}
+/// Sent instead of spawning when a request could not be fulfilled
+#[derive(Event)]
+pub struct InstanceSpawnFailed {
+    pub request: InstanceSpawnRequest,
+    pub reason: SpawnFailureReason,
+}
 
 #[derive(Component)]
 pub struct UnitInformation {
@@ -90,84 +238,116 @@ pub struct UnitInformation {
     pub unit_type: UnitType,
     pub civilisation: Civilisation,
     pub thumbnail: String,
-    pub stats: UnitStats,
     pub unit_info: String,
     pub unit_cost: HashMap<ResourceType, f32>,
 }
 impl Plugin for InstanceSpawner {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, populate_units)
-            .add_systems(Update, spawn)
-            .add_event::<InstanceSpawnRequest>();
+        app.add_plugins((UnitSpecificationLoaderPlugin, MeshColliders))
+            .add_systems(
+                Update,
+                (
+                    spawn.after(update_unit_specifications),
+                    report_failed_spawns.after(spawn),
+                ),
+            )
+            .init_resource::<PlacementSettings>()
+            .add_event::<InstanceSpawnRequest>()
+            .add_event::<InstanceSpawnFailed>();
         // .add_systems(Update, update_emissiveness.before(spawn));
-        // populate_units(app);
     }
 }
-fn populate_units(mut commands: Commands) {
-    let mut unit_specifications: UnitSpecifications = UnitSpecifications {
-        unit_specifications: HashMap::new(),
+impl UnitInformation {
+    /// Takes over everything from the specification that may change while the unit exists
+    pub fn update(&mut self, unit_specification: &UnitSpecification) {
+        self.unit_name = unit_specification.unit_name.clone();
+        self.thumbnail = unit_specification.icon_path.clone();
+        self.unit_info = unit_specification.unit_info.clone();
+        self.unit_cost = unit_specification.unit_cost.clone();
+    }
+}
+pub fn unit_collider(
+    unit_specification: &UnitSpecification,
+) -> Result<Collider, SpawnFailureReason> {
+    shape_collider(
+        unit_specification.shape.0,
+        unit_specification.dimensions,
+        unit_specification.border_radius,
+        &unit_specification.compound,
+    )
+}
+/// Builds a collider from the dimensions given in a specification. Dimensions are half extents,
+/// ships are expected to point along the z axis.
+fn shape_collider(
+    shape: ShapeType,
+    dimensions: Vec3,
+    border_radius: f32,
+    compound: &[ColliderPart],
+) -> Result<Collider, SpawnFailureReason> {
+    let radius: f32 = dimensions.x.max(dimensions.z);
+    let collider: Collider = match shape {
+        ShapeType::Ball => Collider::ball(dimensions.max_element()),
+        ShapeType::Capsule => {
+            Collider::capsule_z(dimensions.max_element() / 2.0, dimensions.min_element())
+        }
+        ShapeType::Cuboid => Collider::cuboid(dimensions.x, dimensions.y, dimensions.z),
+        ShapeType::Cylinder => Collider::cylinder(dimensions.y, radius),
+        ShapeType::Cone => Collider::cone(dimensions.y, radius),
+        ShapeType::Segment => Collider::segment(
+            Vec3::new(0.0, 0.0, -dimensions.z),
+            Vec3::new(0.0, 0.0, dimensions.z),
+        ),
+        ShapeType::Triangle => Collider::triangle(
+            Vec3::new(-dimensions.x, 0.0, -dimensions.z),
+            Vec3::new(dimensions.x, 0.0, -dimensions.z),
+            Vec3::new(0.0, 0.0, dimensions.z),
+        ),
+        // The border is added on top of the inner shape, so shrink it to keep the outer extents
+        ShapeType::RoundCuboid => {
+            let inner: Vec3 = (dimensions - Vec3::splat(border_radius)).max(Vec3::ZERO);
+            Collider::round_cuboid(inner.x, inner.y, inner.z, border_radius)
+        }
+        ShapeType::RoundCylinder => Collider::round_cylinder(
+            (dimensions.y - border_radius).max(0.0),
+            (radius - border_radius).max(0.0),
+            border_radius,
+        ),
+        ShapeType::RoundCone => Collider::round_cone(
+            (dimensions.y - border_radius).max(0.0),
+            (radius - border_radius).max(0.0),
+            border_radius,
+        ),
+        ShapeType::RoundTriangle => {
+            let inner: Vec3 = (dimensions - Vec3::splat(border_radius)).max(Vec3::ZERO);
+            Collider::round_triangle(
+                Vec3::new(-inner.x, 0.0, -inner.z),
+                Vec3::new(inner.x, 0.0, -inner.z),
+                Vec3::new(0.0, 0.0, inner.z),
+                border_radius,
+            )
+        }
+        ShapeType::Compound => {
+            let mut parts: Vec<(Vec3, Quat, Collider)> = Vec::new();
+            for part in compound {
+                parts.push((
+                    part.offset,
+                    part.rotation,
+                    shape_collider(
+                        part.shape.0,
+                        part.dimensions,
+                        part.border_radius,
+                        &part.compound,
+                    )?,
+                ));
+            }
+            if parts.is_empty() {
+                return Err(SpawnFailureReason::UnsupportedShape(shape));
+            }
+            Collider::compound(parts)
+        }
+        shape => return Err(SpawnFailureReason::UnsupportedShape(shape)),
     };
-    todo!("Read from file");
-    // unit_specifications.unit_specifications.insert(
-    //     (Civilisation::Greek, UnitType::Cruiser),
-    //     UnitSpecification {
-    //         file_path: "./assets/3d_models/units/greek/cruiser/greek_cruiser.gltf".into(),
-    //         scene: "Scene0".to_owned(),
-    //         icon_path: "./3d_models/units/greek/cruiser/greek_cruiser_thumbnail.png".into(),
-    //         unit_name: "Andreia Class Cruiser".into(),
-    //         movable: true,
-    //         shape: ShapeTypeSerializable(ShapeType::Capsule),
-    //         dimensions: Vec3 {
-    //             x: 1.0,
-    //             y: 1.0,
-    //             z: 2.0,
-    //         },
-    //         prescaling: 0.1,
-    //         base_stats: UnitStats(Vec::new()),
-    //     },
-    // );
-    // unit_specifications.unit_specifications.insert(
-    //     (Civilisation::Greek, UnitType::MiningStation),
-    //     UnitSpecification {
-    //         file_path: "./assets/3d_models/units/greek/mining_rig/mining_rig.gltf".into(),
-    //         scene: "Scene0".to_owned(),
-    //         icon_path: "./3d_models/units/greek/mining_rig/mining_rig_thumbnail.png".into(),
-    //         unit_name: "Hephaestus Mining Station".into(),
-    //         movable: true,
-    //         shape: ShapeTypeSerializable(ShapeType::Capsule),
-    //         dimensions: Vec3 {
-    //             x: 1.0,
-    //             y: 1.0,
-    //             z: 2.0,
-    //         },
-    //         prescaling: 0.05,
-    //         base_stats: UnitStats(vec![
-    //             UnitStat::MaxMiningDist(1.5),
-    //             UnitStat::BaseMiningRate(24.0),
-    //             UnitStat::BonusMiningRate((ResourceType::Plotanium, 5.0)),
-    //         ]),
-    //     },
-    // );
-    // unit_specifications.unit_specifications.insert(
-    //     (Civilisation::Greek, UnitType::Spacestation),
-    //     UnitSpecification {
-    //         file_path: "./assets/3d_models/buildings/greek/spacestation.glb".into(),
-    //         scene: "Scene0".to_owned(),
-    //         icon_path: "./3d_models/buildings/greek/spacestation_thumbnail.png".into(),
-    //         unit_name: "Akinetos Space Station".into(),
-    //         movable: false,
-    //         shape: ShapeTypeSerializable(ShapeType::Ball),
-    //         dimensions: Vec3 {
-    //             x: 50.0,
-    //             y: 50.0,
-    //             z: 30.0,
-    //         },
-    //         prescaling: 0.02,
-    //         base_stats: UnitStats(Vec::new()),
-    //     },
-    // );
-
-    commands.insert_resource(unit_specifications);
+    Ok(collider)
 }
 #[derive(Component)]
 pub struct EntityWrapper {
@@ -175,102 +355,157 @@ pub struct EntityWrapper {
 }
 fn spawn(
     mut spawn_requests: EventReader<InstanceSpawnRequest>,
+    mut pending_requests: Local<Vec<InstanceSpawnRequest>>,
     mut commands: Commands,
     unit_specifications: Res<UnitSpecifications>,
+    unit_specification_handles: Res<UnitSpecificationHandles>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     asset_server: Res<AssetServer>,
+    mut failed_events: EventWriter<InstanceSpawnFailed>,
+    rapier_context: Res<RapierContext>,
+    movement_grid: Res<MovementGrid>,
+    placement_settings: Res<PlacementSettings>,
 ) {
-    for spawn_request in spawn_requests.read() {
-        if let Some(unit_specification) = unit_specifications
+    // Requests sent before the unit files are read (e.g. on startup) are held back
+    pending_requests.extend(spawn_requests.read().cloned());
+    if !unit_specification_handles.settled(&asset_server) {
+        return;
+    }
+    let mut placed_this_frame: Vec<(Vec3, f32)> = Vec::new();
+    for spawn_request in pending_requests.drain(..) {
+        let Some(unit_specification) = unit_specifications
             .unit_specifications
             .get(&(spawn_request.civilisation, spawn_request.unit_type.clone()))
-        {
-            let texture_handle = asset_server.load("textures/selection_texture.png");
-            let material_handle = materials.add(StandardMaterial {
-                base_color_texture: Some(texture_handle),
-                alpha_mode: AlphaMode::Blend,
-                ..default()
+        else {
+            failed_events.send(InstanceSpawnFailed {
+                request: spawn_request,
+                reason: SpawnFailureReason::UnknownUnit,
             });
-            let collider: Collider = match unit_specification.shape.0 {
-                ShapeType::Ball => Collider::ball(unit_specification.dimensions.max_element()),
-                ShapeType::Capsule => Collider::capsule_z(
-                    unit_specification.dimensions.max_element() / 2.0,
-                    unit_specification.dimensions.min_element(),
-                ),
-                shape => {
-                    println!("Shape {:?} not supported", shape);
+            continue;
+        };
+        let texture_handle = asset_server.load(SELECTION_TEXTURE);
+        let material_handle = materials.add(StandardMaterial {
+            base_color_texture: Some(texture_handle),
+            alpha_mode: AlphaMode::Blend,
+            ..default()
+        });
+        // Mesh based colliders are attached once the model is loaded
+        let collider: Option<Collider> = if is_mesh_shape(unit_specification.shape.0) {
+            None
+        } else {
+            match unit_collider(unit_specification) {
+                Ok(collider) => Some(collider),
+                Err(reason) => {
+                    failed_events.send(InstanceSpawnFailed {
+                        request: spawn_request,
+                        reason,
+                    });
                     continue;
                 }
-            };
-            let parent_id = commands
-                .spawn((
-                    SceneBundle {
-                        transform: Transform::from_xyz(
-                            spawn_request.location.x,
-                            spawn_request.location.y,
-                            spawn_request.location.z,
-                        )
+            }
+        };
+        let radius: f32 =
+            unit_specification.dimensions.max_element() * unit_specification.prescaling;
+        let Some(location) = find_free_location(
+            spawn_request.location,
+            radius,
+            &placement_settings,
+            &rapier_context,
+            &movement_grid,
+            &placed_this_frame,
+        ) else {
+            failed_events.send(InstanceSpawnFailed {
+                request: spawn_request,
+                reason: SpawnFailureReason::NoFreeSpace,
+            });
+            continue;
+        };
+        placed_this_frame.push((location, radius));
+        let parent_id = commands
+            .spawn((
+                SceneBundle {
+                    transform: Transform::from_translation(location)
                         .with_scale(Vec3::splat(unit_specification.prescaling)),
-                        scene: asset_server.load(
-                            unit_specification
-                                .file_path
-                                .clone()
-                                .replace("./assets/", "")
-                                + "#"
-                                + &unit_specification.scene,
-                        ),
+                    scene: asset_server.load(
+                        unit_specification
+                            .file_path
+                            .clone()
+                            .replace("./assets/", "")
+                            + "#"
+                            + &unit_specification.scene,
+                    ),
+                    ..default()
+                },
+                Selectable {},
+                UnitInformation {
+                    unit_name: unit_specification.unit_name.clone(),
+                    unit_type: spawn_request.unit_type.clone(),
+                    civilisation: spawn_request.civilisation,
+                    thumbnail: unit_specification.icon_path.clone(),
+                    unit_info: unit_specification.unit_info.clone(),
+                    unit_cost: unit_specification.unit_cost.clone(),
+                },
+                Stats::from(&unit_specification.base_stats),
+                RigidBody::KinematicPositionBased,
+                GravityScale(0.0),
+                RenderLayers::layer(RenderLayerMap::Main as usize),
+                // ContextMenuActions {},
+            ))
+            .with_children(|parent| {
+                parent.spawn((
+                    MaterialMeshBundle {
+                        mesh: meshes.add(Plane3d::default().mesh().size(
+                            selection_circle_size(unit_specification),
+                            selection_circle_size(unit_specification),
+                        )),
+                        material: material_handle,
+                        transform: Transform::from_scale(Vec3::splat(1.0)),
+                        visibility: Visibility::Hidden,
                         ..default()
                     },
-                    Selectable {},
-                    UnitInformation {
-                        unit_name: unit_specification.unit_name.clone(),
-                        unit_type: spawn_request.unit_type.clone(),
-                        civilisation: spawn_request.civilisation,
-                        thumbnail: unit_specification.icon_path.clone(),
-                        stats: unit_specification.base_stats.clone(),
-                        unit_info: unit_specification.unit_info.clone(),
-                        unit_cost: unit_specification.unit_cost.clone(),
-                    },
-                    RigidBody::KinematicPositionBased,
-                    collider,
-                    GravityScale(0.0),
+                    SelectionCircle,
                     RenderLayers::layer(RenderLayerMap::Main as usize),
-                    // ContextMenuActions {},
-                ))
-                .with_children(|parent| {
-                    parent.spawn((
-                        MaterialMeshBundle {
-                            mesh: meshes.add(Plane3d::default().mesh().size(
-                                2.5 * unit_specification.dimensions.max_element(),
-                                2.5 * unit_specification.dimensions.max_element(),
-                            )),
-                            material: material_handle,
-                            transform: Transform::from_scale(Vec3::splat(1.0)),
-                            visibility: Visibility::Hidden,
-                            ..default()
-                        },
-                        SelectionCircle,
-                        RenderLayers::layer(RenderLayerMap::Main as usize),
-                    ));
-                    parent.spawn((
-                        MaterialMeshBundle {
-                            mesh: meshes.add(Plane3d::default().mesh().size(10.0, 10.0)),
-                            material: materials.add(StandardMaterial {
-                                base_color: Color::srgba(0.0, 1.0, 0.0, 0.5),
-                                ..Default::default()
-                            }),
-                            ..default()
-                        },
-                        RenderLayers::layer(RenderLayerMap::Minimap as usize),
-                    ));
-                })
-                .id();
+                ));
+                parent.spawn((
+                    MaterialMeshBundle {
+                        mesh: meshes.add(Plane3d::default().mesh().size(10.0, 10.0)),
+                        material: materials.add(StandardMaterial {
+                            base_color: Color::srgba(0.0, 1.0, 0.0, 0.5),
+                            ..Default::default()
+                        }),
+                        ..default()
+                    },
+                    RenderLayers::layer(RenderLayerMap::Minimap as usize),
+                ));
+            })
+            .id();
 
-            if unit_specification.movable {
-                commands.entity(parent_id).insert(Movable {});
-            }
+        if let Some(collider) = collider {
+            commands.entity(parent_id).insert(collider);
+        } else {
+            commands
+                .entity(parent_id)
+                .insert(PendingMeshCollider::new(unit_specification, &asset_server));
+        }
+        if unit_specification.movable {
+            commands
+                .entity(parent_id)
+                .insert((Movable::default(), OrderQueue::default()));
+        } else {
+            commands.entity(parent_id).insert(GridObstacle);
         }
         // commands.entity(entity).remove::<InstanceSpawnRequest>();
     }
 }
+fn report_failed_spawns(mut failed_events: EventReader<InstanceSpawnFailed>) {
+    for failed_event in failed_events.read() {
+        warn!(
+            "Could not spawn {} {} at {}: {}",
+            failed_event.request.civilisation,
+            failed_event.request.unit_type,
+            failed_event.request.location,
+            failed_event.reason
+        );
+    }
+}
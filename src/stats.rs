@@ -0,0 +1,263 @@
+use crate::{
+    player_controller::Civilisation,
+    resources::ResourceType,
+    spawner::{UnitStat, UnitStats},
+};
+use bevy::{prelude::*, utils::HashMap};
+
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub enum StatId {
+    MaxMiningDist,
+    BaseMiningRate,
+    BonusMiningRate(ResourceType),
+    Speed,
+    Acceleration,
+    Deceleration,
+    TurnRate,
+    Hull,
+    SensorRange,
+}
+impl StatId {
+    pub fn with_value(self, value: f32) -> UnitStat {
+        match self {
+            StatId::MaxMiningDist => UnitStat::MaxMiningDist(value),
+            StatId::BaseMiningRate => UnitStat::BaseMiningRate(value),
+            StatId::BonusMiningRate(resource_type) => {
+                UnitStat::BonusMiningRate((resource_type, value))
+            }
+            StatId::Speed => UnitStat::Speed(value),
+            StatId::Acceleration => UnitStat::Acceleration(value),
+            StatId::Deceleration => UnitStat::Deceleration(value),
+            StatId::TurnRate => UnitStat::TurnRate(value),
+            StatId::Hull => UnitStat::Hull(value),
+            StatId::SensorRange => UnitStat::SensorRange(value),
+        }
+    }
+}
+impl UnitStat {
+    pub fn id(&self) -> StatId {
+        match self {
+            UnitStat::MaxMiningDist(_) => StatId::MaxMiningDist,
+            UnitStat::BaseMiningRate(_) => StatId::BaseMiningRate,
+            UnitStat::BonusMiningRate((resource_type, _)) => {
+                StatId::BonusMiningRate(*resource_type)
+            }
+            UnitStat::Speed(_) => StatId::Speed,
+            UnitStat::Acceleration(_) => StatId::Acceleration,
+            UnitStat::Deceleration(_) => StatId::Deceleration,
+            UnitStat::TurnRate(_) => StatId::TurnRate,
+            UnitStat::Hull(_) => StatId::Hull,
+            UnitStat::SensorRange(_) => StatId::SensorRange,
+        }
+    }
+    pub fn value(&self) -> f32 {
+        match self {
+            UnitStat::MaxMiningDist(value)
+            | UnitStat::BaseMiningRate(value)
+            | UnitStat::BonusMiningRate((_, value))
+            | UnitStat::Speed(value)
+            | UnitStat::Acceleration(value)
+            | UnitStat::Deceleration(value)
+            | UnitStat::TurnRate(value)
+            | UnitStat::Hull(value)
+            | UnitStat::SensorRange(value) => *value,
+        }
+    }
+}
+
+/// Where a modifier comes from, so it can be lifted again when the source goes away
+#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+pub enum ModifierSource {
+    Civilisation(Civilisation),
+    Tech(String),
+    Aura(Entity),
+}
+#[derive(Clone, Copy, Debug)]
+pub enum ModifierKind {
+    Additive(f32),
+    Multiplicative(f32),
+}
+#[derive(Clone, Debug)]
+pub struct StatModifier {
+    pub source: ModifierSource,
+    pub kind: ModifierKind,
+}
+
+/// Stats of a unit. The effective value of every stat is kept up to date whenever its base
+/// value or modifiers change: (base + all additive modifiers) * all multiplicative modifiers
+#[derive(Component, Default)]
+pub struct Stats {
+    base: HashMap<StatId, f32>,
+    modifiers: HashMap<StatId, Vec<StatModifier>>,
+    values: HashMap<StatId, f32>,
+}
+impl From<&UnitStats> for Stats {
+    fn from(unit_stats: &UnitStats) -> Self {
+        let mut stats: Stats = Stats::default();
+        stats.set_base_stats(unit_stats);
+        stats
+    }
+}
+impl Stats {
+    /// Effective value of the stat, None if the unit neither has a base value nor modifiers for it
+    pub fn get(&self, stat: StatId) -> Option<f32> {
+        self.values.get(&stat).copied()
+    }
+    /// Replaces all base values, e.g. after the unit file changed. Modifiers are kept.
+    pub fn set_base_stats(&mut self, unit_stats: &UnitStats) {
+        self.base = unit_stats
+            .iter()
+            .map(|unit_stat| (unit_stat.id(), unit_stat.value()))
+            .collect();
+        self.recalculate_all();
+    }
+    pub fn add_modifier(&mut self, stat: StatId, modifier: StatModifier) {
+        self.modifiers.entry(stat).or_default().push(modifier);
+        self.recalculate(stat);
+    }
+    pub fn remove_modifiers(&mut self, source: &ModifierSource) {
+        for modifiers in self.modifiers.values_mut() {
+            modifiers.retain(|modifier| modifier.source != *source);
+        }
+        self.modifiers.retain(|_, modifiers| !modifiers.is_empty());
+        self.recalculate_all();
+    }
+    fn recalculate_all(&mut self) {
+        self.values.clear();
+        let stats: Vec<StatId> = self
+            .base
+            .keys()
+            .chain(self.modifiers.keys())
+            .copied()
+            .collect();
+        for stat in stats {
+            self.recalculate(stat);
+        }
+    }
+    fn recalculate(&mut self, stat: StatId) {
+        let base: Option<f32> = self.base.get(&stat).copied();
+        let modifiers: &[StatModifier] = self.modifiers.get(&stat).map_or(&[], Vec::as_slice);
+        if base.is_none() && modifiers.is_empty() {
+            self.values.remove(&stat);
+            return;
+        }
+        let mut additive: f32 = 0.0;
+        let mut multiplicative: f32 = 1.0;
+        for modifier in modifiers {
+            match modifier.kind {
+                ModifierKind::Additive(value) => additive += value,
+                ModifierKind::Multiplicative(value) => multiplicative *= value,
+            }
+        }
+        self.values
+            .insert(stat, (base.unwrap_or(0.0) + additive) * multiplicative);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> Stats {
+        Stats::from(&UnitStats(vec![
+            UnitStat::Speed(2.0),
+            UnitStat::Hull(100.0),
+        ]))
+    }
+    fn modifier(source: ModifierSource, kind: ModifierKind) -> StatModifier {
+        StatModifier { source, kind }
+    }
+
+    #[test]
+    fn modifiers_stack_additive_before_multiplicative() {
+        let mut stats: Stats = stats();
+        stats.add_modifier(
+            StatId::Speed,
+            modifier(
+                ModifierSource::Tech("engines".to_owned()),
+                ModifierKind::Multiplicative(2.0),
+            ),
+        );
+        stats.add_modifier(
+            StatId::Speed,
+            modifier(
+                ModifierSource::Tech("fuel".to_owned()),
+                ModifierKind::Additive(1.0),
+            ),
+        );
+        stats.add_modifier(
+            StatId::Speed,
+            modifier(
+                ModifierSource::Civilisation(Civilisation::Greek),
+                ModifierKind::Additive(0.5),
+            ),
+        );
+        stats.add_modifier(
+            StatId::Speed,
+            modifier(
+                ModifierSource::Tech("hulls".to_owned()),
+                ModifierKind::Multiplicative(1.5),
+            ),
+        );
+        assert_eq!(
+            stats.get(StatId::Speed),
+            Some((2.0 + 1.0 + 0.5) * 2.0 * 1.5)
+        );
+        assert_eq!(stats.get(StatId::Hull), Some(100.0));
+    }
+
+    #[test]
+    fn removing_a_source_restores_the_value() {
+        let mut stats: Stats = stats();
+        let aura: ModifierSource = ModifierSource::Tech("aura".to_owned());
+        stats.add_modifier(
+            StatId::Speed,
+            modifier(aura.clone(), ModifierKind::Additive(3.0)),
+        );
+        stats.add_modifier(
+            StatId::Hull,
+            modifier(aura.clone(), ModifierKind::Multiplicative(0.5)),
+        );
+        stats.add_modifier(
+            StatId::Speed,
+            modifier(
+                ModifierSource::Tech("fuel".to_owned()),
+                ModifierKind::Multiplicative(2.0),
+            ),
+        );
+        assert_eq!(stats.get(StatId::Speed), Some(10.0));
+        assert_eq!(stats.get(StatId::Hull), Some(50.0));
+        stats.remove_modifiers(&aura);
+        assert_eq!(stats.get(StatId::Speed), Some(4.0));
+        assert_eq!(stats.get(StatId::Hull), Some(100.0));
+    }
+
+    #[test]
+    fn modifiers_alone_create_a_stat_until_they_are_removed() {
+        let mut stats: Stats = stats();
+        let source: ModifierSource = ModifierSource::Civilisation(Civilisation::Greek);
+        assert_eq!(stats.get(StatId::SensorRange), None);
+        stats.add_modifier(
+            StatId::SensorRange,
+            modifier(source.clone(), ModifierKind::Additive(5.0)),
+        );
+        assert_eq!(stats.get(StatId::SensorRange), Some(5.0));
+        stats.remove_modifiers(&source);
+        assert_eq!(stats.get(StatId::SensorRange), None);
+    }
+
+    #[test]
+    fn new_base_stats_keep_the_modifiers() {
+        let mut stats: Stats = stats();
+        stats.add_modifier(
+            StatId::Speed,
+            modifier(
+                ModifierSource::Tech("fuel".to_owned()),
+                ModifierKind::Multiplicative(2.0),
+            ),
+        );
+        stats.set_base_stats(&UnitStats(vec![UnitStat::Speed(3.0)]));
+        assert_eq!(stats.get(StatId::Speed), Some(6.0));
+        assert_eq!(stats.get(StatId::Hull), None);
+    }
+}
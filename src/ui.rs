@@ -3,10 +3,10 @@ use std::process;
 use crate::ownable::{Selectable, Selected};
 use crate::player_controller::{ContextMenuAction, LocalPlayer, PlayerInfo};
 use crate::player_controller::{DeselectEvent, RayHit, RenderLayerMap};
+use crate::production::{ProductionQueue, ProductionRequest};
 use crate::resources::{ResourceStockpiles, ResourceType};
-use crate::spawner::{
-    InstanceSpawnRequest, UnitInformation, UnitSpecification, UnitSpecifications,
-};
+use crate::spawner::{UnitInformation, UnitSpecification, UnitSpecifications};
+use crate::unit_loader::UnitSpecificationChanged;
 use bevy::core_pipeline::Skybox;
 use bevy::diagnostic::DiagnosticsStore;
 use bevy::render::camera::ClearColorConfig;
@@ -26,6 +26,8 @@ const MAIN_UI_BACKGROUND: Color = Color::srgba(
     0x14 as f32 / 256.0,
     0xF0 as f32 / 256.0,
 );
+/// Edge length in pixels of unit thumbnails in the selection info
+pub const THUMBNAIL_SIZE: f32 = 60.0;
 const MAIN_UI_TEXT: Color = Color::srgb(12.0 / 256.0, 11.0 / 256.0, 13.0 / 256.0);
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 enum UIType {
@@ -54,6 +56,7 @@ impl Plugin for GameUI {
                     catch_interaction,
                     button_system,
                     update_resources,
+                    refresh_selection_info.after(populate_lower_ui),
                 ),
             )
             .add_event::<RayHit>()
@@ -558,8 +561,8 @@ fn update_selection_info(
     let thumbnail = commands
         .spawn(NodeBundle {
             style: Style {
-                width: Val::Px(60.0),
-                height: Val::Px(60.0),
+                width: Val::Px(THUMBNAIL_SIZE),
+                height: Val::Px(THUMBNAIL_SIZE),
                 ..default()
             },
             background_color: ICON_BACKGROUND.into(),
@@ -625,41 +628,36 @@ fn button_system(
         ),
         (Changed<Interaction>, With<Button>),
     >,
-    player_info: Query<&PlayerInfo, With<LocalPlayer>>,
-    selected_entities: Query<&Transform, With<Selected>>,
-    mut spawn_events: EventWriter<InstanceSpawnRequest>,
+    player: Query<Entity, With<LocalPlayer>>,
+    selected_buildings: Query<Entity, (With<Selected>, With<ProductionQueue>)>,
+    mut production_requests: EventWriter<ProductionRequest>,
 ) {
-    if let Ok(player_info) = player_info.get_single() {
-        for transform in selected_entities.iter() {
-            for (interaction, action, mut background_color, mut border_color) in
-                &mut interaction_query
-            {
-                match *interaction {
-                    Interaction::Pressed => {
-                        match action {
-                            ContextMenuAction::Build(unit_type) => {
-                                spawn_events.send(InstanceSpawnRequest {
-                                    location: Vec3 {
-                                        x: transform.translation.x + 2.0,
-                                        y: 2.0,
-                                        z: transform.translation.z + 1.0,
-                                    },
+    if let Ok(player) = player.get_single() {
+        for (interaction, action, mut background_color, mut border_color) in &mut interaction_query
+        {
+            match *interaction {
+                Interaction::Pressed => {
+                    match action {
+                        ContextMenuAction::Build(unit_type) => {
+                            for building in selected_buildings.iter() {
+                                production_requests.send(ProductionRequest {
+                                    building,
+                                    player,
                                     unit_type: unit_type.clone(),
-                                    civilisation: player_info.civilisation,
                                 });
                             }
-                        };
-                        *background_color = PRESSED_BUTTON.into();
-                        border_color.0 = Color::BLACK;
-                    }
-                    Interaction::Hovered => {
-                        border_color.0 = Color::BLACK;
-                        *background_color = HOVERED_BUTTON.into();
-                    }
-                    Interaction::None => {
-                        border_color.0 = Color::BLACK;
-                        *background_color = NORMAL_BUTTON.into();
-                    }
+                        }
+                    };
+                    *background_color = PRESSED_BUTTON.into();
+                    border_color.0 = Color::BLACK;
+                }
+                Interaction::Hovered => {
+                    border_color.0 = Color::BLACK;
+                    *background_color = HOVERED_BUTTON.into();
+                }
+                Interaction::None => {
+                    border_color.0 = Color::BLACK;
+                    *background_color = NORMAL_BUTTON.into();
                 }
             }
         }
@@ -674,66 +672,70 @@ fn update_context_menu(
     unit_specifications: &Res<UnitSpecifications>,
     player_info: &PlayerInfo,
 ) {
-    // let mut buttons: Vec<Entity> = Vec::new();
-    // for action in context_menu_actions {
-    //     match action {
-    //         ContextMenuAction::Build(unit_type) => {
-    //             let unit_information: &UnitSpecification = &unit_specifications.unit_specifications
-    //                 [&(player_info.civilisation, unit_type.clone())];
-    //             buttons.push(
-    //                 commands
-    //                     .spawn(NodeBundle {
-    //                         style: Style {
-    //                             width: Val::Px(70.0),
-    //                             height: Val::Px(70.0),
-    //                             flex_direction: FlexDirection::ColumnReverse,
-    //                             ..default()
-    //                         },
-    //                         // background_color: ICON_BACKGROUND.into(),
-    //                         ..default()
-    //                     })
-    //                     .with_children(|parent| {
-    //                         parent.spawn((
-    //                             ButtonBundle {
-    //                                 style: Style {
-    //                                     width: Val::Px(65.0),
-    //                                     height: Val::Px(65.0),
-    //                                     justify_content: JustifyContent::Center,
-    //                                     align_items: AlignItems::Center,
-    //                                     border: UiRect::percent(5.0, 5.0, 5.0, 5.0),
-    //                                     ..default()
-    //                                 },
-    //                                 image: UiImage {
-    //                                     texture: asset_server.load(&unit_information.icon_path),
-    //                                     ..default()
-    //                                 },
-    //                                 background_color: NORMAL_BUTTON.into(),
-    //                                 border_color: Color::BLACK.into(),
-    //                                 ..default()
-    //                             },
-    //                             action.clone(),
-    //                         ));
-    //                     })
-    //                     .id(),
-    //             );
-    //             let container = commands
-    //                 .spawn(NodeBundle {
-    //                     style: Style {
-    //                         width: Val::Percent(80.0),
-    //                         height: Val::Percent(80.0),
-    //                         align_items: AlignItems::Start,
-    //                         justify_content: JustifyContent::Start,
-    //                         flex_direction: FlexDirection::Row,
-    //                         ..default()
-    //                     },
-    //                     ..default()
-    //                 })
-    //                 .push_children(&buttons)
-    //                 .id();
-    //             commands.entity(context_menu_content).add_child(container);
-    //         }
-    //     }
-    // }
+    let mut buttons: Vec<Entity> = Vec::new();
+    for action in context_menu_actions {
+        match action {
+            ContextMenuAction::Build(unit_type) => {
+                let Some(unit_information): Option<&UnitSpecification> = unit_specifications
+                    .unit_specifications
+                    .get(&(player_info.civilisation, unit_type.clone()))
+                else {
+                    continue;
+                };
+                buttons.push(
+                    commands
+                        .spawn(NodeBundle {
+                            style: Style {
+                                width: Val::Px(70.0),
+                                height: Val::Px(70.0),
+                                flex_direction: FlexDirection::ColumnReverse,
+                                ..default()
+                            },
+                            // background_color: ICON_BACKGROUND.into(),
+                            ..default()
+                        })
+                        .with_children(|parent| {
+                            parent.spawn((
+                                ButtonBundle {
+                                    style: Style {
+                                        width: Val::Px(65.0),
+                                        height: Val::Px(65.0),
+                                        justify_content: JustifyContent::Center,
+                                        align_items: AlignItems::Center,
+                                        border: UiRect::percent(5.0, 5.0, 5.0, 5.0),
+                                        ..default()
+                                    },
+                                    image: UiImage {
+                                        texture: asset_server.load(&unit_information.icon_path),
+                                        ..default()
+                                    },
+                                    background_color: NORMAL_BUTTON.into(),
+                                    border_color: Color::BLACK.into(),
+                                    ..default()
+                                },
+                                action.clone(),
+                            ));
+                        })
+                        .id(),
+                );
+            }
+        }
+    }
+    let container = commands
+        .spawn(NodeBundle {
+            style: Style {
+                width: Val::Percent(80.0),
+                height: Val::Percent(80.0),
+                align_items: AlignItems::Start,
+                justify_content: JustifyContent::Start,
+                flex_direction: FlexDirection::Row,
+                ..default()
+            },
+            ..default()
+        })
+        .push_children(&buttons)
+        .id();
+    commands.entity(context_menu_content).add_child(container);
 }
 fn populate_lower_ui(
     mut commands: Commands,
@@ -786,6 +788,36 @@ fn populate_lower_ui(
         }
     }
 }
+/// Redraws the selection info when the unit file of the selected unit was edited
+fn refresh_selection_info(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut changed_events: EventReader<UnitSpecificationChanged>,
+    selected_units: Query<&UnitInformation, With<Selected>>,
+    ui_elements: Query<(Entity, &UIContent)>,
+) {
+    for changed_event in changed_events.read() {
+        let Some(unit_information) = selected_units.iter().find(|unit_information| {
+            unit_information.civilisation == changed_event.civilisation
+                && unit_information.unit_type == changed_event.unit_type
+        }) else {
+            continue;
+        };
+        let (selection_info_content, _): (Entity, _) = ui_elements
+            .into_iter()
+            .find(|(_, content)| **content == UIContent::Content(UIType::SelectionInfo))
+            .unwrap();
+        commands
+            .entity(selection_info_content)
+            .despawn_descendants();
+        update_selection_info(
+            &mut commands,
+            unit_information,
+            &asset_server,
+            selection_info_content,
+        );
+    }
+}
 // remove all children
 fn clear_ui(
     mut commands: Commands,
@@ -1,31 +1,45 @@
 mod a_star;
+mod avoidance;
 mod civilisation;
 mod environment;
+mod flow_field;
+mod formation;
+mod hpa_star;
+mod image_capture;
+mod mesh_collider;
 mod movable;
+mod move_requests;
+mod obstacles;
+mod orders;
 mod ownable;
+mod path_debug;
+mod pathfinding;
+mod placement;
 mod player_controller;
+mod production;
 mod resource_collection;
 mod resources;
 mod spawner;
+mod stats;
 mod ui;
+mod unit_loader;
 mod utils;
 
 use std::{
-    f32::consts::TAU,
-    // fs,
-    // io::BufWriter
+    f32::consts::{FRAC_PI_2, TAU},
+    path::{Path, PathBuf},
 };
 
-use bevy::color::palettes::css::RED;
 use bevy::prelude::*;
-use bevy::sprite::Anchor;
 use bevy::{
-    color::palettes::css::{DARK_GRAY, DARK_OLIVEGREEN},
+    color::palettes::css::DARK_GRAY,
     core_pipeline::{bloom::BloomSettings, tonemapping::Tonemapping},
     diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin},
+    input::{
+        keyboard::{Key, KeyboardInput},
+        ButtonState,
+    },
     math::Vec3,
-    // prelude::*,
-    // reflect::DynamicTypePath,
     render::{
         camera::RenderTarget,
         render_resource::{
@@ -33,39 +47,364 @@ use bevy::{
         },
         view::RenderLayers,
     },
+    utils::HashMap,
 };
 use bevy_file_dialog::prelude::*;
-use bevy_lunex::prelude::*;
-// use bevy_rapier3d::parry::shape::ShapeType;
-// use resources::ResourceType;
-// use spawner::{UnitSpecification, UnitStats};
-// use utils::ShapeTypeSerializable;
+use bevy_rapier3d::{prelude::*, rapier::prelude::ShapeType};
+use environment::CELL_SIZE;
+use image::{imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+use image_capture::{CaptureImage, ImageCapturePlugin, ImageCaptured};
+use mesh_collider::{is_mesh_shape, MeshColliders, PendingMeshCollider};
+use resources::ResourceType;
+use spawner::{
+    selection_circle_size, unit_collider, UnitSpecification, UnitStats, UnitType, SELECTION_TEXTURE,
+};
+use stats::StatId;
+use strum::IntoEnumIterator;
+use ui::THUMBNAIL_SIZE;
+use unit_loader::{UnitSpecificationFile, UNIT_SPECIFICATION_EXTENSION};
+use utils::ShapeTypeSerializable;
 
 const NORMAL_BUTTON: Color = Color::srgb(0.15, 0.15, 0.15);
 const HOVERED_BUTTON: Color = Color::srgb(0.25, 0.25, 0.25);
 const PRESSED_BUTTON: Color = Color::srgb(0.35, 0.75, 0.35);
+const FIELD_BACKGROUND: Color = Color::srgb(0.1, 0.1, 0.1);
+const FIELD_BORDER: Color = Color::srgb(0.4, 0.4, 0.4);
+const FOCUSED_FIELD_BORDER: Color = Color::WHITE;
+const INVALID_FIELD_BORDER: Color = Color::srgb(0.8, 0.2, 0.2);
+/// Render layer of the gizmos, which only the preview camera sees
+const PREVIEW_OVERLAY_LAYER: usize = 1;
+/// Upper bound of reference grid cells per side, for very small prescaling values
+const MAX_GRID_CELLS: u32 = 200;
+const GRID_COLOR: Color = Color::srgb(0.5, 0.5, 0.5);
+/// Summed channel difference from which a pixel no longer counts as background
+const THUMBNAIL_BACKGROUND_TOLERANCE: u32 = 24;
+const FONT: &str = "fonts/android-insomnia-font/AndroidInsomniaRegular.ttf";
+/// Shapes the shape button cycles through. Compound parts can not be edited, so compound
+/// shapes are only offered for specifications which were loaded with parts.
+const EDITABLE_SHAPES: [ShapeType; 11] = [
+    ShapeType::Ball,
+    ShapeType::Cuboid,
+    ShapeType::Capsule,
+    ShapeType::Cylinder,
+    ShapeType::Cone,
+    ShapeType::RoundCuboid,
+    ShapeType::RoundCylinder,
+    ShapeType::RoundCone,
+    ShapeType::TriMesh,
+    ShapeType::ConvexPolyhedron,
+    ShapeType::RoundConvexPolyhedron,
+];
 
-struct TextFileContents;
+/// Marks the dialogs loading and saving unit files
+struct UnitFile;
+/// Marks the dialog picking the glTF model
+struct ModelFile;
 #[derive(Component)]
 struct RenderedUnit(String);
-struct PrintFilePath;
+/// Render target of the preview camera
+#[derive(Resource)]
+struct PreviewImage(Handle<Image>);
+#[derive(Component)]
+struct PreviewCamera;
+#[derive(Component)]
+struct SelectionCirclePreview;
+/// Collider, selection circle and grid drawn over the preview
+#[derive(Resource)]
+struct PreviewOverlays {
+    visible: bool,
+    /// Overlays are hidden until the requested thumbnail has been captured
+    capturing: bool,
+}
+impl Default for PreviewOverlays {
+    fn default() -> Self {
+        PreviewOverlays {
+            visible: true,
+            capturing: false,
+        }
+    }
+}
+/// The unit file being edited
+#[derive(Resource)]
+struct EditedUnit {
+    unit_type: UnitType,
+    specification: UnitSpecification,
+}
+impl Default for EditedUnit {
+    fn default() -> Self {
+        EditedUnit {
+            unit_type: UnitType::Cruiser,
+            specification: UnitSpecification {
+                file_path: "./assets/3d_models/units/greek/cruiser/greek_cruiser.gltf".to_owned(),
+                scene: "Scene0".to_owned(),
+                icon_path: "".to_owned(),
+                unit_name: "".to_owned(),
+                movable: true,
+                collector: false,
+                produces: Vec::new(),
+                shape: ShapeTypeSerializable(ShapeType::Capsule),
+                dimensions: Vec3::ONE,
+                border_radius: 0.0,
+                compound: Vec::new(),
+                prescaling: 0.1,
+                base_stats: UnitStats(Vec::new()),
+                unit_info: "".to_owned(),
+                unit_cost: HashMap::new(),
+                build_time: 0.0,
+            },
+        }
+    }
+}
+impl EditedUnit {
+    /// Asset path of the scene shown in the preview
+    fn scene_path(&self) -> String {
+        format!(
+            "{}#{}",
+            self.specification.file_path.replace("./assets/", ""),
+            self.specification.scene
+        )
+    }
+}
+/// A value of the specification which is edited as text
+#[derive(Clone, Copy, PartialEq)]
+enum SpecField {
+    UnitType,
+    UnitName,
+    UnitInfo,
+    FilePath,
+    Scene,
+    IconPath,
+    Produces,
+    Dimension(usize),
+    BorderRadius,
+    Prescaling,
+    BuildTime,
+    Stat(StatId),
+    Cost(ResourceType),
+}
+impl SpecField {
+    fn all() -> Vec<SpecField> {
+        let mut fields: Vec<SpecField> = vec![
+            SpecField::UnitType,
+            SpecField::UnitName,
+            SpecField::UnitInfo,
+            SpecField::FilePath,
+            SpecField::Scene,
+            SpecField::IconPath,
+            SpecField::Produces,
+            SpecField::Dimension(0),
+            SpecField::Dimension(1),
+            SpecField::Dimension(2),
+            SpecField::BorderRadius,
+            SpecField::Prescaling,
+            SpecField::BuildTime,
+        ];
+        fields.extend(
+            [
+                StatId::MaxMiningDist,
+                StatId::BaseMiningRate,
+                StatId::Speed,
+                StatId::Acceleration,
+                StatId::Deceleration,
+                StatId::TurnRate,
+                StatId::Hull,
+                StatId::SensorRange,
+            ]
+            .into_iter()
+            .chain(ResourceType::iter().map(StatId::BonusMiningRate))
+            .map(SpecField::Stat),
+        );
+        fields.extend(ResourceType::iter().map(SpecField::Cost));
+        fields
+    }
+    fn label(&self) -> String {
+        match self {
+            SpecField::UnitType => "Unit type".to_owned(),
+            SpecField::UnitName => "Name".to_owned(),
+            SpecField::UnitInfo => "Info".to_owned(),
+            SpecField::FilePath => "Model".to_owned(),
+            SpecField::Scene => "Scene".to_owned(),
+            SpecField::IconPath => "Icon".to_owned(),
+            SpecField::Produces => "Produces".to_owned(),
+            SpecField::Dimension(axis) => format!("Dimension {}", ["x", "y", "z"][*axis]),
+            SpecField::BorderRadius => "Border radius".to_owned(),
+            SpecField::Prescaling => "Prescaling".to_owned(),
+            SpecField::BuildTime => "Build time".to_owned(),
+            SpecField::Stat(stat) => format!("{:?}", stat),
+            SpecField::Cost(resource_type) => format!("Cost {:?}", resource_type),
+        }
+    }
+    fn read(&self, edited_unit: &EditedUnit) -> String {
+        let specification: &UnitSpecification = &edited_unit.specification;
+        match self {
+            SpecField::UnitType => edited_unit.unit_type.id().to_owned(),
+            SpecField::UnitName => specification.unit_name.clone(),
+            SpecField::UnitInfo => specification.unit_info.clone(),
+            SpecField::FilePath => specification.file_path.clone(),
+            SpecField::Scene => specification.scene.clone(),
+            SpecField::IconPath => specification.icon_path.clone(),
+            SpecField::Produces => specification
+                .produces
+                .iter()
+                .map(|unit_type| unit_type.id())
+                .collect::<Vec<&str>>()
+                .join(", "),
+            SpecField::Dimension(axis) => specification.dimensions[*axis].to_string(),
+            SpecField::BorderRadius => specification.border_radius.to_string(),
+            SpecField::Prescaling => specification.prescaling.to_string(),
+            SpecField::BuildTime => specification.build_time.to_string(),
+            SpecField::Stat(stat) => specification
+                .base_stats
+                .iter()
+                .find(|unit_stat| unit_stat.id() == *stat)
+                .map_or(String::new(), |unit_stat| unit_stat.value().to_string()),
+            SpecField::Cost(resource_type) => specification
+                .unit_cost
+                .get(resource_type)
+                .map_or(String::new(), |cost| cost.to_string()),
+        }
+    }
+    /// Parses the text into the specification, leaving it untouched if the text is invalid
+    fn write(&self, text: &str, edited_unit: &mut EditedUnit) -> Result<(), String> {
+        let specification: &mut UnitSpecification = &mut edited_unit.specification;
+        match self {
+            SpecField::UnitType => {
+                if text.trim().is_empty() {
+                    return Err("The unit type needs an id".to_owned());
+                }
+                edited_unit.unit_type = UnitType::from(text.trim().to_owned());
+            }
+            SpecField::UnitName => specification.unit_name = text.to_owned(),
+            SpecField::UnitInfo => specification.unit_info = text.to_owned(),
+            SpecField::FilePath => specification.file_path = text.to_owned(),
+            SpecField::Scene => specification.scene = text.to_owned(),
+            SpecField::IconPath => specification.icon_path = text.to_owned(),
+            SpecField::Produces => {
+                specification.produces = text
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|id| !id.is_empty())
+                    .map(|id| UnitType::from(id.to_owned()))
+                    .collect()
+            }
+            SpecField::Dimension(axis) => specification.dimensions[*axis] = parse_number(text)?,
+            SpecField::BorderRadius => specification.border_radius = parse_number(text)?,
+            SpecField::Prescaling => specification.prescaling = parse_number(text)?,
+            SpecField::BuildTime => specification.build_time = parse_number(text)?,
+            SpecField::Stat(stat) => {
+                let value: Option<f32> = parse_optional_number(text)?;
+                specification
+                    .base_stats
+                    .retain(|unit_stat| unit_stat.id() != *stat);
+                if let Some(value) = value {
+                    specification.base_stats.push(stat.with_value(value));
+                }
+            }
+            SpecField::Cost(resource_type) => match parse_optional_number(text)? {
+                Some(cost) => {
+                    specification.unit_cost.insert(*resource_type, cost);
+                }
+                None => {
+                    specification.unit_cost.remove(resource_type);
+                }
+            },
+        }
+        Ok(())
+    }
+}
+fn parse_number(text: &str) -> Result<f32, String> {
+    text.trim()
+        .parse()
+        .map_err(|_| format!("{} is not a number", text))
+}
+/// An empty text means the value is not set
+fn parse_optional_number(text: &str) -> Result<Option<f32>, String> {
+    if text.trim().is_empty() {
+        Ok(None)
+    } else {
+        parse_number(text).map(Some)
+    }
+}
+#[derive(Component)]
+struct TextField {
+    field: SpecField,
+    text: String,
+    valid: bool,
+}
+#[derive(Resource, Default)]
+struct FocusedField(Option<Entity>);
+#[derive(Component, Clone, Copy)]
+enum EditorButton {
+    PickModel,
+    Load,
+    Save,
+    CaptureThumbnail,
+    Overlays,
+    Movable,
+    Collector,
+    Shape,
+}
+impl EditorButton {
+    fn label(&self, edited_unit: &EditedUnit, overlays: &PreviewOverlays) -> String {
+        match self {
+            EditorButton::PickModel => "Pick model".to_owned(),
+            EditorButton::Load => "Load".to_owned(),
+            EditorButton::Save => "Save".to_owned(),
+            EditorButton::CaptureThumbnail => "Capture thumbnail".to_owned(),
+            EditorButton::Overlays => format!("Overlays: {}", overlays.visible),
+            EditorButton::Movable => format!("Movable: {}", edited_unit.specification.movable),
+            EditorButton::Collector => {
+                format!("Collector: {}", edited_unit.specification.collector)
+            }
+            EditorButton::Shape => format!("Shape: {:?}", edited_unit.specification.shape.0),
+        }
+    }
+}
+/// Rewrites every text field from the edited unit, e.g. after a file was loaded
+#[derive(Event)]
+struct RefreshForm;
+
 fn main() {
     App::new()
         .add_plugins((
             DefaultPlugins,
             FrameTimeDiagnosticsPlugin,
             FileDialogPlugin::new()
-                // allow saving of files marked with TextFileContents
-                // allow loading of files marked with TextFileContents
-                .with_pick_file::<PrintFilePath>(),
-            UiPlugin,
+                .with_save_file::<UnitFile>()
+                .with_load_file::<UnitFile>()
+                .with_pick_file::<ModelFile>(),
+            ImageCapturePlugin,
+            RapierPhysicsPlugin::<NoUserData>::default(),
+            RapierDebugRenderPlugin::default(),
+            MeshColliders,
         ))
+        .init_resource::<EditedUnit>()
+        .init_resource::<PreviewOverlays>()
+        .init_resource::<FocusedField>()
+        .add_event::<RefreshForm>()
         .add_systems(Startup, setup)
         .add_systems(
             Update,
             (
-                // button_system,
-                file_picked,
+                focus_text_fields,
+                edit_focused_field.after(focus_text_fields),
+                editor_buttons.after(edit_focused_field),
+                model_picked,
+                unit_file_loaded,
+                unit_file_saved,
+                thumbnail_captured,
+                refresh_form
+                    .after(editor_buttons)
+                    .after(model_picked)
+                    .after(unit_file_loaded)
+                    .after(thumbnail_captured),
+                update_text_fields.after(refresh_form),
+                update_button_labels.after(refresh_form),
+                update_rendered_unit.after(refresh_form),
+                update_preview_overlays.after(update_rendered_unit),
+                show_preview_overlays.after(update_preview_overlays),
+                draw_reference_grid,
+                frame_preview,
                 text_update_system,
                 rotate_rendered,
             ),
@@ -76,64 +415,20 @@ fn main() {
 // A unit struct to help identify the FPS UI component, since there may be many Text components
 #[derive(Component)]
 struct FpsText;
-// fn initialise_mini_map(commands: &mut Commands, mut images: ResMut<Assets<Image>>) -> Entity {
-//     let size = Extent3d {
-//         width: 1024,
-//         height: 1024,
-//         ..default()
-//     };
-//     let mut image = Image {
-//         texture_descriptor: TextureDescriptor {
-//             label: None,
-//             size,
-//             dimension: TextureDimension::D2,
-//             format: TextureFormat::Bgra8UnormSrgb,
-//             mip_level_count: 1,
-//             sample_count: 1,
-//             usage: TextureUsages::TEXTURE_BINDING
-//                 | TextureUsages::COPY_DST
-//                 | TextureUsages::RENDER_ATTACHMENT,
-//             view_formats: &[],
-//         },
-//         ..default()
-//     };
-//     image.resize(size);
-//     let image_handle = images.add(image);
-
-// commands.spawn((
-//     Camera3dBundle {
-//         camera_3d: Camera3d { ..default() },
-//         camera: Camera {
-//             clear_color: ClearColorConfig::Custom(DARK_GRAY.into()),
-//             // render before the "main pass" camera
-//             order: -1,
-//             target: RenderTarget::Image(image_handle.clone()),
-//             hdr: true,
-//             ..default()
-//         },
-//         tonemapping: Tonemapping::BlenderFilmic,
-//         transform: Transform::from_translation(Vec3::new(10.0, 2.0, 0.0))
-//             .looking_at(Vec3::ZERO, Vec3::Y),
-//         ..default()
-//     },
-//     BloomSettings::default(),
-//     // RenderLayers::from_layers(&[1]),
-// ));
-// }
 fn setup(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    images: ResMut<Assets<Image>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+    edited_unit: Res<EditedUnit>,
+    mut gizmo_config_store: ResMut<GizmoConfigStore>,
 ) {
+    // Collider and grid overlays are only drawn into the preview
+    let (gizmo_config, _) = gizmo_config_store.config_mut::<DefaultGizmoConfigGroup>();
+    gizmo_config.render_layers = RenderLayers::layer(PREVIEW_OVERLAY_LAYER);
     // UI camera
-    commands.spawn((
-        MainUi,
-        Camera2dBundle {
-            transform: Transform::from_xyz(0.0, 0.0, 1000.0),
-            ..default()
-        },
-    ));
+    commands.spawn(Camera2dBundle {
+        transform: Transform::from_xyz(0.0, 0.0, 1000.0),
+        ..default()
+    });
 
     commands.spawn(DirectionalLightBundle {
         transform: Transform::from_xyz(1.0, 1.0, 0.0).looking_at(Vec3::ZERO, Vec3::Y),
@@ -158,6 +453,7 @@ fn setup(
             sample_count: 1,
             usage: TextureUsages::TEXTURE_BINDING
                 | TextureUsages::COPY_DST
+                | TextureUsages::COPY_SRC
                 | TextureUsages::RENDER_ATTACHMENT,
             view_formats: &[],
         },
@@ -169,6 +465,7 @@ fn setup(
 
     // Add our texture to asset server and get a handle
     let render_image = asset_server.add(image);
+    commands.insert_resource(PreviewImage(render_image.clone()));
 
     commands.spawn((
         Camera3dBundle {
@@ -187,137 +484,525 @@ fn setup(
             ..default()
         },
         BloomSettings::default(),
-        // RenderLayers::from_layers(&[1]),
+        RenderLayers::from_layers(&[0, PREVIEW_OVERLAY_LAYER]),
+        PreviewCamera,
     ));
+    let font: Handle<Font> = asset_server.load(FONT);
     commands
+        .spawn(NodeBundle {
+            style: Style {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                flex_direction: FlexDirection::Row,
+                column_gap: Val::Px(20.0),
+                padding: UiRect::all(Val::Px(20.0)),
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|root| {
+            root.spawn(ImageBundle {
+                style: Style {
+                    width: Val::Px(512.0),
+                    height: Val::Px(512.0),
+                    ..default()
+                },
+                image: UiImage::new(render_image),
+                ..default()
+            });
+            root.spawn(NodeBundle {
+                style: Style {
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(4.0),
+                    ..default()
+                },
+                ..default()
+            })
+            .with_children(|form| {
+                form.spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Row,
+                        column_gap: Val::Px(10.0),
+                        ..default()
+                    },
+                    ..default()
+                })
+                .with_children(|actions| {
+                    for button in [
+                        EditorButton::PickModel,
+                        EditorButton::Load,
+                        EditorButton::Save,
+                        EditorButton::CaptureThumbnail,
+                        EditorButton::Overlays,
+                    ] {
+                        spawn_editor_button(actions, button, &edited_unit, &font);
+                    }
+                });
+                for field in SpecField::all() {
+                    spawn_text_field(form, field, &edited_unit, &font);
+                    if field == SpecField::IconPath {
+                        for button in [EditorButton::Movable, EditorButton::Collector] {
+                            spawn_editor_button(form, button, &edited_unit, &font);
+                        }
+                    } else if field == SpecField::Produces {
+                        spawn_editor_button(form, EditorButton::Shape, &edited_unit, &font);
+                    }
+                }
+            });
+        });
+}
+fn text_style(font: &Handle<Font>) -> TextStyle {
+    TextStyle {
+        font: font.clone(),
+        font_size: 16.0,
+        color: Color::WHITE,
+    }
+}
+fn spawn_text_field(
+    parent: &mut ChildBuilder,
+    field: SpecField,
+    edited_unit: &EditedUnit,
+    font: &Handle<Font>,
+) {
+    parent
+        .spawn(NodeBundle {
+            style: Style {
+                flex_direction: FlexDirection::Row,
+                align_items: AlignItems::Center,
+                column_gap: Val::Px(10.0),
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|row| {
+            row.spawn(
+                TextBundle::from_section(field.label(), text_style(font)).with_style(Style {
+                    width: Val::Px(250.0),
+                    ..default()
+                }),
+            );
+            row.spawn((
+                ButtonBundle {
+                    style: Style {
+                        width: Val::Px(500.0),
+                        height: Val::Px(24.0),
+                        border: UiRect::all(Val::Px(2.0)),
+                        padding: UiRect::horizontal(Val::Px(4.0)),
+                        align_items: AlignItems::Center,
+                        overflow: Overflow::clip(),
+                        ..default()
+                    },
+                    border_color: FIELD_BORDER.into(),
+                    background_color: FIELD_BACKGROUND.into(),
+                    ..default()
+                },
+                TextField {
+                    field,
+                    text: field.read(edited_unit),
+                    valid: true,
+                },
+            ))
+            .with_children(|input| {
+                input.spawn(TextBundle::from_section(
+                    field.read(edited_unit),
+                    text_style(font),
+                ));
+            });
+        });
+}
+fn spawn_editor_button(
+    parent: &mut ChildBuilder,
+    button: EditorButton,
+    edited_unit: &EditedUnit,
+    font: &Handle<Font>,
+) {
+    parent
         .spawn((
-            // This makes the UI entity able to receive camera data
-            MovableByCamera,
-            // This is our UI system
-            UiTreeBundle::<MainUi>::from(UiTree::new2d("Main Menu")),
+            ButtonBundle {
+                style: Style {
+                    height: Val::Px(24.0),
+                    padding: UiRect::horizontal(Val::Px(8.0)),
+                    align_items: AlignItems::Center,
+                    align_self: AlignSelf::Start,
+                    ..default()
+                },
+                background_color: NORMAL_BUTTON.into(),
+                ..default()
+            },
+            button,
         ))
-        .with_children(|ui| {
-            // Spawn boundary node
-
-            let root = UiLink::<MainUi>::path("Root");
-            ui.spawn((
-                root.clone(),
-                // Link the entity
-                // Specify UI layout
-                UiLayout::window_full().pack::<Base>(),
-            ));
-            ui.spawn((
-                root.add("UnitViewer"),
-                UiLayout::window()
-                    .size(Rw(40.0))
-                    // .size((256.0, 256.0))
-                    .anchor(Anchor::CenterLeft)
-                    .pos((Rh(0.0), Rh(50.0)))
-                    // .align_x(Align::LEFT)
-                    // .scaling(Scaling::Fill)
-                    .pack::<Base>(),
-                UiImage2dBundle::from(render_image),
-                PickingPortal, // You can add this component to send picking events through the viewport.
-            ));
-            let inputs = root.add("Inputs");
-            ui.spawn((
-                // Link the entity
-                // root.add("Inputs"),
-                inputs.clone(),
-                // Specify UI layout
-                UiLayout::window()
-                    .size((Rw(50.0), Rh(80.0)))
-                    .anchor(Anchor::CenterLeft)
-                    .pos((Rl(45.0), Rl(50.0)))
-                    .pack::<Base>(),
-                // Add image to the entity
-                // UiImage2dBundle::from(asset_server.load("textures/ui/greek/button_01.png")),
-            ));
-            let gltf = inputs.add("GLTF");
-
-            ui.spawn((
-                // Link the entity
-                // root.add("Inputs"),
-                gltf.clone(),
-                // Specify UI layout
-                UiLayout::window()
-                    .size((Rw(80.0), Rh(5.0)))
-                    .anchor(Anchor::CenterLeft)
-                    .pos((Rl(10.0), Rl(10.0)))
-                    .pack::<Base>(),
-                // Add image to the entity
-                // UiImage2dBundle::from(asset_server.load("textures/ui/greek/button_01.png")),
-            ));
-            ui.spawn((
-                // Link the entity
-                // root.add("Inputs"),
-                gltf.add("text"),
-                // Specify UI layout
-                UiLayout::window()
-                    .size((Rw(80.0), Rh(100.0)))
-                    .anchor(Anchor::CenterLeft)
-                    .pos((Rl(10.0), Rl(50.0)))
-                    .pack::<Base>(),
-                // Add image to the entity
-                // UiImage2dBundle::from(asset_server.load("textures/ui/greek/button_01.png")),
-            ));
-            ui.spawn((
-                // Link the entity
-                // root.add("Inputs"),
-                gltf.add("button"),
-                // Specify UI layout
-                UiLayout::window()
-                    .size((Rw(10.0), Rh(70.0)))
-                    .anchor(Anchor::CenterLeft)
-                    .pos((Rl(90.0), Rl(50.0)))
-                    .pack::<Base>(),
-                // Add image to the entity
-                UiImage2dBundle::from(asset_server.load("textures/ui/greek/button_01.png")),
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                button.label(edited_unit, &PreviewOverlays::default()),
+                text_style(font),
             ));
         });
+}
+fn focus_text_fields(
+    interactions: Query<(Entity, &Interaction), (Changed<Interaction>, With<TextField>)>,
+    mut focused_field: ResMut<FocusedField>,
+) {
+    for (entity, interaction) in interactions.iter() {
+        if *interaction == Interaction::Pressed {
+            focused_field.0 = Some(entity);
+        }
+    }
+}
+/// Types into the focused field. The specification is updated on every key stroke,
+/// so the preview follows immediately; text which does not parse marks the field red.
+fn edit_focused_field(
+    mut keyboard_events: EventReader<KeyboardInput>,
+    mut focused_field: ResMut<FocusedField>,
+    mut text_fields: Query<&mut TextField>,
+    mut edited_unit: ResMut<EditedUnit>,
+) {
+    let Some(mut text_field) = focused_field
+        .0
+        .and_then(|entity| text_fields.get_mut(entity).ok())
+    else {
+        keyboard_events.clear();
+        return;
+    };
+    for keyboard_event in keyboard_events.read() {
+        if keyboard_event.state != ButtonState::Pressed {
+            continue;
+        }
+        match &keyboard_event.logical_key {
+            Key::Character(characters) => text_field.text.push_str(characters),
+            Key::Space => text_field.text.push(' '),
+            Key::Backspace => {
+                text_field.text.pop();
+            }
+            Key::Enter | Key::Tab | Key::Escape => {
+                focused_field.0 = None;
+                break;
+            }
+            _ => continue,
+        }
+        let result: Result<(), String> = text_field.field.write(&text_field.text, &mut edited_unit);
+        text_field.valid = result.is_ok();
+    }
+}
+fn editor_buttons(
+    mut commands: Commands,
+    mut interactions: Query<
+        (&Interaction, &EditorButton, &mut BackgroundColor),
+        Changed<Interaction>,
+    >,
+    mut edited_unit: ResMut<EditedUnit>,
+    mut focused_field: ResMut<FocusedField>,
+    mut capture_events: EventWriter<CaptureImage>,
+    preview_image: Res<PreviewImage>,
+    mut overlays: ResMut<PreviewOverlays>,
+) {
+    for (interaction, button, mut color) in interactions.iter_mut() {
+        match *interaction {
+            Interaction::Pressed => {
+                *color = PRESSED_BUTTON.into();
+                focused_field.0 = None;
+            }
+            Interaction::Hovered => {
+                *color = HOVERED_BUTTON.into();
+                continue;
+            }
+            Interaction::None => {
+                *color = NORMAL_BUTTON.into();
+                continue;
+            }
+        }
+        match button {
+            EditorButton::PickModel => {
+                commands
+                    .dialog()
+                    .add_filter("glTF", &["gltf", "glb"])
+                    .pick_file_path::<ModelFile>();
+            }
+            EditorButton::Load => {
+                commands
+                    .dialog()
+                    .add_filter("Unit file", &["ron"])
+                    .load_file::<UnitFile>();
+            }
+            EditorButton::Save => {
+                let unit_file = UnitSpecificationFile {
+                    unit_type: edited_unit.unit_type.clone(),
+                    specification: edited_unit.specification.clone(),
+                };
+                match ron::ser::to_string_pretty(&unit_file, ron::ser::PrettyConfig::default()) {
+                    Ok(serialized) => {
+                        commands
+                            .dialog()
+                            .add_filter("Unit file", &["ron"])
+                            .set_file_name(format!(
+                                "{}.{}",
+                                file_stem(&edited_unit.unit_type),
+                                UNIT_SPECIFICATION_EXTENSION
+                            ))
+                            .save_file::<UnitFile>(serialized.into_bytes());
+                    }
+                    Err(error) => error!("Could not serialize the unit: {}", error),
+                }
+            }
+            EditorButton::CaptureThumbnail => {
+                overlays.capturing = true;
+                capture_events.send(CaptureImage(preview_image.0.clone()));
+            }
+            EditorButton::Overlays => overlays.visible = !overlays.visible,
+            EditorButton::Movable => {
+                edited_unit.specification.movable = !edited_unit.specification.movable
+            }
+            EditorButton::Collector => {
+                edited_unit.specification.collector = !edited_unit.specification.collector
+            }
+            EditorButton::Shape => {
+                let has_parts: bool = !edited_unit.specification.compound.is_empty();
+                let shapes: Vec<ShapeType> = EDITABLE_SHAPES
+                    .into_iter()
+                    .chain(has_parts.then_some(ShapeType::Compound))
+                    .collect();
+                let next: usize = shapes
+                    .iter()
+                    .position(|shape| *shape == edited_unit.specification.shape.0)
+                    .map_or(0, |index| (index + 1) % shapes.len());
+                edited_unit.specification.shape = ShapeTypeSerializable(shapes[next]);
+            }
+        }
+    }
+}
+/// Unit files are named after their unit type, e.g. `mining_station.unit.ron`
+fn file_stem(unit_type: &UnitType) -> String {
+    let mut stem: String = String::new();
+    for (index, character) in unit_type.id().chars().enumerate() {
+        if character.is_uppercase() && index > 0 {
+            stem.push('_');
+        }
+        stem.extend(character.to_lowercase());
+    }
+    stem
+}
+fn model_picked(
+    mut picked_events: EventReader<DialogFilePicked<ModelFile>>,
+    mut edited_unit: ResMut<EditedUnit>,
+    mut refresh_events: EventWriter<RefreshForm>,
+) {
+    for picked in picked_events.read() {
+        match asset_path(&picked.path) {
+            Some(path) => {
+                edited_unit.specification.file_path = format!("./assets/{}", path);
+                refresh_events.send(RefreshForm);
+            }
+            None => warn!(
+                "{} is not inside the assets folder and can not be loaded by the game",
+                picked.path.display()
+            ),
+        }
+    }
+}
+/// Path below the `assets` folder, as expected by the asset server
+fn asset_path(path: &Path) -> Option<String> {
+    let components: Vec<String> = path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let assets: usize = components
+        .iter()
+        .rposition(|component| component == "assets")?;
+    Some(components[assets + 1..].join("/"))
+}
+fn unit_file_loaded(
+    mut loaded_events: EventReader<DialogFileLoaded<UnitFile>>,
+    mut edited_unit: ResMut<EditedUnit>,
+    mut refresh_events: EventWriter<RefreshForm>,
+) {
+    for loaded in loaded_events.read() {
+        match ron::de::from_bytes::<UnitSpecificationFile>(&loaded.contents) {
+            Ok(unit_file) => {
+                edited_unit.unit_type = unit_file.unit_type;
+                edited_unit.specification = unit_file.specification;
+                refresh_events.send(RefreshForm);
+            }
+            Err(error) => error!(
+                "{}:{}:{}: {}",
+                loaded.file_name, error.position.line, error.position.col, error.code
+            ),
+        }
+    }
+}
+fn unit_file_saved(mut saved_events: EventReader<DialogFileSaved<UnitFile>>) {
+    for saved in saved_events.read() {
+        info!("Saved {}", saved.file_name);
+    }
+}
+/// Crops the captured preview to the model, scales it to the icon size of the game's UI and
+/// stores it next to the model as `<model>_thumbnail.png`
+fn thumbnail_captured(
+    mut captured_events: EventReader<ImageCaptured>,
+    preview_image: Res<PreviewImage>,
+    mut edited_unit: ResMut<EditedUnit>,
+    mut refresh_events: EventWriter<RefreshForm>,
+    mut overlays: ResMut<PreviewOverlays>,
+) {
+    for captured in captured_events.read() {
+        if captured.source != preview_image.0 {
+            continue;
+        }
+        overlays.capturing = false;
+        let preview: DynamicImage = match captured.image.clone().try_into_dynamic() {
+            Ok(preview) => preview,
+            Err(error) => {
+                error!("Could not convert the preview: {}", error);
+                continue;
+            }
+        };
+        let (x, y, size): (u32, u32, u32) = thumbnail_area(&preview.to_rgba8());
+        let thumbnail: DynamicImage = preview.crop_imm(x, y, size, size).resize_exact(
+            THUMBNAIL_SIZE as u32,
+            THUMBNAIL_SIZE as u32,
+            FilterType::Lanczos3,
+        );
+        let model_path: &Path = Path::new(&edited_unit.specification.file_path);
+        let Some(model_name) = model_path.file_stem() else {
+            warn!("Pick a model before capturing its thumbnail");
+            continue;
+        };
+        let thumbnail_path: PathBuf =
+            model_path.with_file_name(format!("{}_thumbnail.png", model_name.to_string_lossy()));
+        if let Err(error) = thumbnail.save(&thumbnail_path) {
+            error!("Could not write {}: {}", thumbnail_path.display(), error);
+            continue;
+        }
+        info!("Saved thumbnail {}", thumbnail_path.display());
+        // Icons are given relative to the assets folder
+        edited_unit.specification.icon_path =
+            thumbnail_path.to_string_lossy().replace("./assets/", "./");
+        refresh_events.send(RefreshForm);
+    }
+}
+/// Smallest square around everything which differs from the background, with a small margin.
+/// The background colour is taken from the top left pixel.
+fn thumbnail_area(preview: &RgbaImage) -> (u32, u32, u32) {
+    let background: Rgba<u8> = *preview.get_pixel(0, 0);
+    let mut min: UVec2 = UVec2::new(preview.width(), preview.height());
+    let mut max: UVec2 = UVec2::ZERO;
+    for (x, y, pixel) in preview.enumerate_pixels() {
+        let difference: u32 = pixel
+            .0
+            .iter()
+            .zip(background.0.iter())
+            .take(3)
+            .map(|(a, b)| a.abs_diff(*b) as u32)
+            .sum();
+        if difference > THUMBNAIL_BACKGROUND_TOLERANCE {
+            min = min.min(UVec2::new(x, y));
+            max = max.max(UVec2::new(x, y));
+        }
+    }
+    if min.x > max.x {
+        // Nothing but background, keep the whole image
+        return (0, 0, preview.width().min(preview.height()));
+    }
+    let center: UVec2 = (min + max) / 2;
+    let extent: u32 = (max - min).max_element() + 1;
+    let size: u32 = ((extent as f32 * 1.1) as u32)
+        .min(preview.width())
+        .min(preview.height());
+    let x: u32 = center
+        .x
+        .saturating_sub(size / 2)
+        .min(preview.width() - size);
+    let y: u32 = center
+        .y
+        .saturating_sub(size / 2)
+        .min(preview.height() - size);
+    (x, y, size)
+}
+fn refresh_form(
+    mut refresh_events: EventReader<RefreshForm>,
+    mut text_fields: Query<&mut TextField>,
+    edited_unit: Res<EditedUnit>,
+) {
+    if refresh_events.read().count() == 0 {
+        return;
+    }
+    for mut text_field in text_fields.iter_mut() {
+        text_field.text = text_field.field.read(&edited_unit);
+        text_field.valid = true;
+    }
+}
+fn update_text_fields(
+    mut text_fields: Query<(Entity, Ref<TextField>, &Children, &mut BorderColor)>,
+    mut texts: Query<&mut Text>,
+    focused_field: Res<FocusedField>,
+) {
+    for (entity, text_field, children, mut border_color) in text_fields.iter_mut() {
+        if !text_field.is_changed() && !focused_field.is_changed() {
+            continue;
+        }
+        let focused: bool = focused_field.0 == Some(entity);
+        border_color.0 = if !text_field.valid {
+            INVALID_FIELD_BORDER
+        } else if focused {
+            FOCUSED_FIELD_BORDER
+        } else {
+            FIELD_BORDER
+        };
+        for child in children.iter() {
+            if let Ok(mut text) = texts.get_mut(*child) {
+                text.sections[0].value = if focused {
+                    format!("{}|", text_field.text)
+                } else {
+                    text_field.text.clone()
+                };
+            }
+        }
+    }
+}
+fn update_button_labels(
+    buttons: Query<(&EditorButton, &Children)>,
+    mut texts: Query<&mut Text>,
+    edited_unit: Res<EditedUnit>,
+    overlays: Res<PreviewOverlays>,
+) {
+    if !edited_unit.is_changed() && !overlays.is_changed() {
+        return;
+    }
+    for (button, children) in buttons.iter() {
+        for child in children.iter() {
+            if let Ok(mut text) = texts.get_mut(*child) {
+                text.sections[0].value = button.label(&edited_unit, &overlays);
+            }
+        }
+    }
+}
+/// Swaps the previewed model whenever the model or scene of the specification changes
+fn update_rendered_unit(
+    mut commands: Commands,
+    rendered_units: Query<(Entity, &RenderedUnit)>,
+    edited_unit: Res<EditedUnit>,
+    asset_server: Res<AssetServer>,
+) {
+    if !edited_unit.is_changed() {
+        return;
+    }
+    let scene_path: String = edited_unit.scene_path();
+    if rendered_units
+        .iter()
+        .any(|(_, rendered_unit)| rendered_unit.0 == scene_path)
+    {
+        return;
+    }
+    for (entity, _) in rendered_units.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
     commands.spawn((
         SceneBundle {
-            scene: asset_server.load(
-                GltfAssetLabel::Scene(0)
-                    .from_asset("3d_models/units/greek/cruiser/greek_cruiser.gltf"),
-            ),
+            scene: asset_server.load(scene_path.clone()),
             ..default()
         },
-        RenderedUnit("3d_models/units/greek/cruiser/greek_cruiser.gltf".to_owned()),
+        RenderedUnit(scene_path),
         RenderLayers::layer(1),
     ));
 }
-// fn button_system(
-//     mut commands: Commands,
-//     mut interaction_query: Query<
-//         (
-//             &Interaction,
-//             &mut BackgroundColor,
-//             &mut BorderColor,
-//             &Children,
-//         ),
-//         (Changed<Interaction>, With<Button>),
-//     >,
-// ) {
-//     for (interaction, mut color, mut border_color, children) in &mut interaction_query {
-//         match *interaction {
-//             Interaction::Pressed => {
-//                 // commands.dialog().load_file::<TextFileContents>();
-//                 commands.dialog().pick_file_path::<PrintFilePath>();
-//                 *color = PRESSED_BUTTON.into();
-//                 border_color.0 = RED.into();
-//             }
-//             Interaction::Hovered => {
-//                 *color = HOVERED_BUTTON.into();
-//                 border_color.0 = Color::WHITE;
-//             }
-//             Interaction::None => {
-//                 *color = NORMAL_BUTTON.into();
-//                 border_color.0 = Color::BLACK;
-//             }
-//         }
-//     }
-// }
 
 fn text_update_system(
     diagnostics: Res<DiagnosticsStore>,
@@ -341,43 +1026,102 @@ fn rotate_rendered(mut cameras: Query<&mut Transform, With<RenderedUnit>>, timer
         transform.rotate_y(0.3 * TAU * timer.delta_seconds());
     }
 }
-
-fn pick(mut commands: Commands) {
-    commands.dialog().pick_file_path::<PrintFilePath>();
+/// Gives the previewed model the collider and selection circle the spawner would give it.
+/// Both live in model space, so they are compared to the unscaled model.
+fn update_preview_overlays(
+    mut commands: Commands,
+    rendered_units: Query<(Entity, Ref<RenderedUnit>)>,
+    selection_circles: Query<Entity, With<SelectionCirclePreview>>,
+    edited_unit: Res<EditedUnit>,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, rendered_unit) in rendered_units.iter() {
+        if !edited_unit.is_changed() && !rendered_unit.is_added() {
+            continue;
+        }
+        let specification: &UnitSpecification = &edited_unit.specification;
+        let mut unit_commands = commands.entity(entity);
+        unit_commands.remove::<(Collider, PendingMeshCollider)>();
+        if is_mesh_shape(specification.shape.0) {
+            unit_commands.insert(PendingMeshCollider::new(specification, &asset_server));
+        } else {
+            match unit_collider(specification) {
+                Ok(collider) => {
+                    unit_commands.insert(collider);
+                }
+                Err(reason) => warn!("Can not preview the collider: {}", reason),
+            }
+        }
+        for selection_circle in selection_circles.iter() {
+            commands.entity(selection_circle).despawn_recursive();
+        }
+        let selection_circle: Entity = commands
+            .spawn((
+                MaterialMeshBundle {
+                    mesh: meshes.add(Plane3d::default().mesh().size(
+                        selection_circle_size(specification),
+                        selection_circle_size(specification),
+                    )),
+                    material: materials.add(StandardMaterial {
+                        base_color_texture: Some(asset_server.load(SELECTION_TEXTURE)),
+                        alpha_mode: AlphaMode::Blend,
+                        ..default()
+                    }),
+                    ..default()
+                },
+                SelectionCirclePreview,
+            ))
+            .id();
+        commands.entity(entity).add_child(selection_circle);
+    }
+}
+fn show_preview_overlays(
+    overlays: Res<PreviewOverlays>,
+    mut gizmo_config_store: ResMut<GizmoConfigStore>,
+    mut selection_circles: Query<&mut Visibility, With<SelectionCirclePreview>>,
+) {
+    let visible: bool = overlays.visible && !overlays.capturing;
+    let (gizmo_config, _) = gizmo_config_store.config_mut::<DefaultGizmoConfigGroup>();
+    gizmo_config.enabled = visible;
+    for mut visibility in selection_circles.iter_mut() {
+        *visibility = if visible {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+/// Movement grid cells around the model. The model is shown unscaled, so a cell spans
+/// `CELL_SIZE / prescaling` model units.
+fn draw_reference_grid(mut gizmos: Gizmos, edited_unit: Res<EditedUnit>) {
+    let specification: &UnitSpecification = &edited_unit.specification;
+    if specification.prescaling <= 0.0 {
+        return;
+    }
+    let spacing: f32 = CELL_SIZE / specification.prescaling;
+    let cell_count: u32 = ((1.2 * selection_circle_size(specification) / spacing).ceil() as u32)
+        .clamp(1, MAX_GRID_CELLS);
+    gizmos.grid(
+        Vec3::ZERO,
+        Quat::from_rotation_x(FRAC_PI_2),
+        UVec2::splat(cell_count),
+        Vec2::splat(spacing),
+        GRID_COLOR,
+    );
+}
+/// Moves the preview camera so the whole selection circle stays in view
+fn frame_preview(
+    mut cameras: Query<&mut Transform, With<PreviewCamera>>,
+    edited_unit: Res<EditedUnit>,
+) {
+    if !edited_unit.is_changed() {
+        return;
+    }
+    let distance: f32 = (1.5 * selection_circle_size(&edited_unit.specification)).max(10.0);
+    for mut transform in cameras.iter_mut() {
+        *transform = Transform::from_translation(Vec3::new(distance, 0.2 * distance, 0.0))
+            .looking_at(Vec3::ZERO, Vec3::Y);
+    }
 }
-
-fn file_picked(mut ev_picked: EventReader<DialogFilePicked<PrintFilePath>>) {
-    for ev in ev_picked.read() {
-        eprintln!("File picked, path {:?}", ev.path);
-    }
-}
-// fn main() {
-//     let test_instance = UnitSpecification {
-//         file_path: "./assets/3d_models/buildings/greek/spacestation.glb".into(),
-//         scene: "Scene0".to_owned(),
-//         icon_path: "./3d_models/buildings/greek/spacestation_thumbnail.png".into(),
-//         unit_name: "Akinetos Space Station".into(),
-//         movable: false,
-//         shape: ShapeTypeSerializable(ShapeType::Ball),
-//         dimensions: Vec3 {
-//             x: 50.0,
-//             y: 50.0,
-//             z: 30.0,
-//         },
-//         prescaling: 0.02,
-//         base_stats: UnitStats(Vec::new()),
-//         unit_info: "The greek Akinetos Space Station. This is the hub of all activity in a system."
-//             .into(),
-//         unit_cost: vec![(ResourceType::Plotanium, 100.0)].into_iter().collect(),
-//     };
-//     let serialized: String =
-//         ron::ser::to_string_pretty(&test_instance, ron::ser::PrettyConfig::default()).unwrap();
-//     let file = fs::File::create("greek_spacestation.ron").expect("Could not create a file");
-
-//     // ron::ser::to_writer_pretty(
-//     //     BufWriter::new(file),
-//     //     &test_instance,
-//     //     ron::ser::PrettyConfig::default(),
-//     // );
-//     println!("{}", serialized.clone(),);
-// }
@@ -0,0 +1,336 @@
+use crate::{
+    mesh_collider::{is_mesh_shape, PendingMeshCollider},
+    player_controller::Civilisation,
+    spawner::{
+        unit_collider, UnitInformation, UnitSpecification, UnitSpecifications, UnitType,
+        UnitTypeRegistry,
+    },
+    stats::Stats,
+};
+use bevy::{
+    asset::{io::Reader, AssetLoadFailedEvent, AssetLoader, AsyncReadExt, LoadContext, LoadState},
+    prelude::*,
+    utils::HashMap,
+};
+use bevy_rapier3d::geometry::Collider;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Folder below `assets/` holding one sub folder of unit files per civilisation,
+/// e.g. `assets/units/greek/cruiser.unit.ron`.
+pub const UNIT_SPECIFICATION_FOLDER: &str = "units";
+pub const UNIT_SPECIFICATION_EXTENSION: &str = "unit.ron";
+
+/// On disk layout of a unit file. The civilisation is taken from the folder the file lives in.
+#[derive(Serialize, Deserialize)]
+pub struct UnitSpecificationFile {
+    pub unit_type: UnitType,
+    pub specification: UnitSpecification,
+}
+
+#[derive(Asset, TypePath)]
+pub struct UnitSpecificationAsset {
+    pub civilisation: Civilisation,
+    pub unit_type: UnitType,
+    pub specification: UnitSpecification,
+}
+
+#[derive(Debug)]
+pub enum UnitSpecificationLoaderError {
+    Io(io::Error),
+    UnknownCivilisation(PathBuf),
+    Parse {
+        path: PathBuf,
+        error: ron::error::SpannedError,
+    },
+}
+impl fmt::Display for UnitSpecificationLoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnitSpecificationLoaderError::Io(error) => write!(f, "{}", error),
+            UnitSpecificationLoaderError::UnknownCivilisation(path) => write!(
+                f,
+                "{}: unit files have to be placed in a folder named after a civilisation",
+                path.display()
+            ),
+            UnitSpecificationLoaderError::Parse { path, error } => write!(
+                f,
+                "{}:{}:{}: {}",
+                path.display(),
+                error.position.line,
+                error.position.col,
+                error.code
+            ),
+        }
+    }
+}
+impl Error for UnitSpecificationLoaderError {}
+impl From<io::Error> for UnitSpecificationLoaderError {
+    fn from(error: io::Error) -> Self {
+        UnitSpecificationLoaderError::Io(error)
+    }
+}
+
+#[derive(Default)]
+pub struct UnitSpecificationLoader;
+impl AssetLoader for UnitSpecificationLoader {
+    type Asset = UnitSpecificationAsset;
+    type Settings = ();
+    type Error = UnitSpecificationLoaderError;
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<UnitSpecificationAsset, Self::Error> {
+        let path: PathBuf = load_context.path().to_path_buf();
+        let civilisation: Civilisation = civilisation_from_path(&path)
+            .ok_or_else(|| UnitSpecificationLoaderError::UnknownCivilisation(path.clone()))?;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let unit_file: UnitSpecificationFile = ron::de::from_bytes(&bytes)
+            .map_err(|error| UnitSpecificationLoaderError::Parse { path, error })?;
+        Ok(UnitSpecificationAsset {
+            civilisation,
+            unit_type: unit_file.unit_type,
+            specification: unit_file.specification,
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[UNIT_SPECIFICATION_EXTENSION]
+    }
+}
+fn civilisation_from_path(path: &Path) -> Option<Civilisation> {
+    path.parent()?.file_name()?.to_str()?.parse().ok()
+}
+
+/// Sent after a unit file changed on disk and the already spawned units of that type were updated
+#[derive(Event)]
+pub struct UnitSpecificationChanged {
+    pub civilisation: Civilisation,
+    pub unit_type: UnitType,
+}
+
+/// Strong handles to every unit file found on startup, keeping them alive and
+/// allowing to check whether all of them have been processed.
+#[derive(Resource, Default)]
+pub struct UnitSpecificationHandles(pub Vec<Handle<UnitSpecificationAsset>>);
+impl UnitSpecificationHandles {
+    /// True once every unit file has either been loaded or failed to load
+    pub fn settled(&self, asset_server: &AssetServer) -> bool {
+        self.0.iter().all(|handle| {
+            matches!(
+                asset_server.load_state(handle),
+                LoadState::Loaded | LoadState::Failed(_)
+            )
+        })
+    }
+}
+/// Civilisation and unit type every loaded unit file declared last, so the old ones can be
+/// dropped when a reloaded file changes them
+#[derive(Resource, Default)]
+struct DeclaredUnitTypes(HashMap<AssetId<UnitSpecificationAsset>, (Civilisation, UnitType)>);
+
+pub struct UnitSpecificationLoaderPlugin;
+impl Plugin for UnitSpecificationLoaderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<UnitSpecificationAsset>()
+            .init_asset_loader::<UnitSpecificationLoader>()
+            .insert_resource(UnitSpecifications {
+                unit_specifications: HashMap::new(),
+            })
+            .init_resource::<UnitSpecificationHandles>()
+            .init_resource::<UnitTypeRegistry>()
+            .init_resource::<DeclaredUnitTypes>()
+            .add_event::<UnitSpecificationChanged>()
+            .add_systems(Startup, load_unit_specifications)
+            .add_systems(
+                Update,
+                (
+                    update_unit_specifications,
+                    reload_spawned_units.after(update_unit_specifications),
+                    report_unit_specification_errors,
+                ),
+            );
+    }
+}
+fn load_unit_specifications(
+    asset_server: Res<AssetServer>,
+    mut unit_specification_handles: ResMut<UnitSpecificationHandles>,
+) {
+    let unit_folder: PathBuf = Path::new("assets").join(UNIT_SPECIFICATION_FOLDER);
+    let civilisation_folders = match fs::read_dir(&unit_folder) {
+        Ok(folders) => folders,
+        Err(error) => {
+            error!("Could not read {}: {}", unit_folder.display(), error);
+            return;
+        }
+    };
+    for civilisation_folder in civilisation_folders.flatten() {
+        let Ok(unit_files) = fs::read_dir(civilisation_folder.path()) else {
+            continue;
+        };
+        for unit_file in unit_files.flatten() {
+            let file_name: String = unit_file.file_name().to_string_lossy().into_owned();
+            if !file_name.ends_with(&format!(".{}", UNIT_SPECIFICATION_EXTENSION)) {
+                continue;
+            }
+            let asset_path: String = format!(
+                "{}/{}/{}",
+                UNIT_SPECIFICATION_FOLDER,
+                civilisation_folder.file_name().to_string_lossy(),
+                file_name
+            );
+            unit_specification_handles
+                .0
+                .push(asset_server.load(asset_path));
+        }
+    }
+}
+/// Mirrors the loaded unit files into [`UnitSpecifications`] and registers the unit types they
+/// declare. Works on the assets directly rather than on asset events, so the map is complete in
+/// the same frame the files finish loading. Unit types a reloaded file no longer declares are
+/// dropped again.
+pub fn update_unit_specifications(
+    unit_specification_assets: Res<Assets<UnitSpecificationAsset>>,
+    mut unit_specifications: ResMut<UnitSpecifications>,
+    mut unit_type_registry: ResMut<UnitTypeRegistry>,
+    mut declared_unit_types: ResMut<DeclaredUnitTypes>,
+    unit_specification_handles: Res<UnitSpecificationHandles>,
+    asset_server: Res<AssetServer>,
+) {
+    if !unit_specification_assets.is_changed() {
+        return;
+    }
+    for (id, unit_asset) in unit_specification_assets.iter() {
+        let declared: (Civilisation, UnitType) =
+            (unit_asset.civilisation, unit_asset.unit_type.clone());
+        if let Some(previous) = declared_unit_types
+            .0
+            .insert(id, declared.clone())
+            .filter(|previous| *previous != declared)
+        {
+            unit_specifications.unit_specifications.remove(&previous);
+            let (_, previous_type) = previous;
+            if !declared_unit_types
+                .0
+                .values()
+                .any(|(_, unit_type)| *unit_type == previous_type)
+                && unit_type_registry.unregister(&previous_type)
+            {
+                info!("Unregistered unit type {}", previous_type.id());
+            }
+        }
+        if unit_type_registry.register(unit_asset.unit_type.clone()) {
+            info!("Registered unit type {}", unit_asset.unit_type.id());
+        }
+        unit_specifications.unit_specifications.insert(
+            (unit_asset.civilisation, unit_asset.unit_type.clone()),
+            unit_asset.specification.clone(),
+        );
+    }
+    // Only complain about missing products once every unit file had the chance to load
+    if !unit_specification_handles.settled(&asset_server) {
+        return;
+    }
+    for ((civilisation, unit_type), unit_specification) in &unit_specifications.unit_specifications
+    {
+        for product in &unit_specification.produces {
+            if !unit_type_registry.contains(product) {
+                warn!(
+                    "{} {} produces the unknown unit type {}",
+                    civilisation,
+                    unit_type.id(),
+                    product.id()
+                );
+            } else if !unit_specifications
+                .unit_specifications
+                .contains_key(&(*civilisation, product.clone()))
+            {
+                warn!(
+                    "{} {} produces {}, which has no unit file for that civilisation",
+                    civilisation,
+                    unit_type.id(),
+                    product.id()
+                );
+            }
+        }
+    }
+}
+/// Applies edited unit files to every unit of that civilisation and type already in the game
+fn reload_spawned_units(
+    mut commands: Commands,
+    mut asset_events: EventReader<AssetEvent<UnitSpecificationAsset>>,
+    unit_specification_assets: Res<Assets<UnitSpecificationAsset>>,
+    asset_server: Res<AssetServer>,
+    mut units: Query<(
+        Entity,
+        &mut UnitInformation,
+        &mut Transform,
+        Option<&mut Stats>,
+    )>,
+    mut changed_events: EventWriter<UnitSpecificationChanged>,
+) {
+    for asset_event in asset_events.read() {
+        let AssetEvent::Modified { id } = asset_event else {
+            continue;
+        };
+        let Some(unit_asset) = unit_specification_assets.get(*id) else {
+            continue;
+        };
+        let specification: &UnitSpecification = &unit_asset.specification;
+        let collider: Option<Collider> = match unit_collider(specification) {
+            Ok(collider) => Some(collider),
+            Err(_) if is_mesh_shape(specification.shape.0) => None,
+            Err(reason) => {
+                warn!(
+                    "Keeping the colliders of {} {}: {}",
+                    unit_asset.civilisation, unit_asset.unit_type, reason
+                );
+                None
+            }
+        };
+        for (entity, mut unit_information, mut transform, stats) in units.iter_mut() {
+            if unit_information.civilisation != unit_asset.civilisation
+                || unit_information.unit_type != unit_asset.unit_type
+            {
+                continue;
+            }
+            unit_information.update(specification);
+            if let Some(mut stats) = stats {
+                stats.set_base_stats(&specification.base_stats);
+            }
+            transform.scale = Vec3::splat(specification.prescaling);
+            if let Some(collider) = &collider {
+                commands.entity(entity).insert(collider.clone());
+            } else if is_mesh_shape(specification.shape.0) {
+                commands
+                    .entity(entity)
+                    .insert(PendingMeshCollider::new(specification, &asset_server));
+            }
+        }
+        info!(
+            "Reloaded {} {}",
+            unit_asset.civilisation, unit_asset.unit_type
+        );
+        changed_events.send(UnitSpecificationChanged {
+            civilisation: unit_asset.civilisation,
+            unit_type: unit_asset.unit_type.clone(),
+        });
+    }
+}
+fn report_unit_specification_errors(
+    mut failed_events: EventReader<AssetLoadFailedEvent<UnitSpecificationAsset>>,
+) {
+    for failed_event in failed_events.read() {
+        error!(
+            "Could not load unit specification {}: {}",
+            failed_event.path, failed_event.error
+        );
+    }
+}